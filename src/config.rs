@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use regex_lite::Regex;
+use serde::Deserialize;
+
+const CONFIG_DIR: &str = "psa-kb-switcher";
+const CONFIG_FILE: &str = "config.toml";
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything that used to be a compile-time constant.
+/// Missing keys (or a missing file) fall back to the built-in defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub font: FontSection,
+    pub icon: IconSection,
+    pub indicators: IndicatorSection,
+    pub labels: LabelSection,
+    pub tray: TraySection,
+    pub floating: FloatingSection,
+    pub memory: MemorySection,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FontSection {
+    /// fontconfig pattern, e.g. "DejaVu Sans:bold"
+    pub family: String,
+    /// Patterns tried in order when `family` is not installed
+    pub fallbacks: Vec<String>,
+    /// Explicit font file; skips fontconfig entirely when set
+    pub path: Option<PathBuf>,
+    /// Glyph height in pixels (the old `PxScale` of 16)
+    pub size: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IconSection {
+    pub size: u16,
+    pub background: Color,
+    pub foreground: Color,
+    pub background_mode: BackgroundMode,
+    /// Look of the icon while the group is locked (the usual case)
+    pub locked_style: GroupStyle,
+    /// Look of the icon while another group is only active temporarily,
+    /// e.g. while holding a grp:switch key or after a latch
+    pub temporary_style: GroupStyle,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupStyle {
+    /// Label on the background color
+    #[default]
+    Filled,
+    /// Like `filled`, with a frame in the foreground color
+    Outlined,
+    /// Foreground and background colors swapped
+    Inverted,
+}
+
+/// Text shown for each layout. Templates may use `{code}` (ISO 639-1 code
+/// of the layout's language, "EN"), `{layout}` ("us"), `{variant}`
+/// ("dvorak") and `{name}` (XKB group name); "\n" starts a second line.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LabelSection {
+    pub template: String,
+    /// Per-layout templates, keyed by "layout(variant)", group name or
+    /// layout, e.g. `"us(dvorak)" = "DV"`
+    pub overrides: HashMap<String, String>,
+    /// Mark groups that would otherwise share a label with the start of
+    /// their XKB variant ("dv" for us(dvorak)) in the bottom right corner
+    pub badges: bool,
+}
+
+/// Marks drawn on the icon while a lock key is on: an underline for
+/// Caps Lock, dots in the top corners for Num Lock (right) and Scroll Lock (left)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndicatorSection {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+    pub color: Color,
+}
+
+/// How a translucent `background` is shown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackgroundMode {
+    /// Real alpha through the tray's ARGB visual; falls back to
+    /// `parent-relative` when the tray does not offer one
+    #[default]
+    Argb,
+    /// Label drawn over a copy of the panel's own background pixels,
+    /// for trays without a compositor
+    ParentRelative,
+    /// Always an opaque square, alpha is ignored
+    Solid,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraySection {
+    /// Seconds to wait for a tray at startup. Unset waits forever, or not at
+    /// all with the `floating` fallback.
+    pub wait_timeout: Option<u64>,
+    /// What to do when no tray shows up in time, or the tray goes away
+    pub fallback: TrayFallback,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrayFallback {
+    /// Exit at startup; hide the icon until a new tray appears later on
+    #[default]
+    Exit,
+    /// Show the icon as a small always-on-top window instead
+    Floating,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FloatingSection {
+    /// Let clicks pass through to the windows below. The widget can then
+    /// neither be clicked nor dragged.
+    pub click_through: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemorySection {
+    pub mode: MemoryMode,
+}
+
+/// What the layout is remembered for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryMode {
+    /// One layout for everything (plain XKB behaviour)
+    Global,
+    /// Each top-level window keeps its own layout
+    #[default]
+    Window,
+    /// Each virtual desktop keeps its own layout
+    Desktop,
+}
+
+/// Forces a layout when a matching window is focused for the first time.
+/// Every given condition has to match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Either part of WM_CLASS (instance or class), case-insensitive
+    pub class: Option<String>,
+    /// Regular expression searched for in the window title
+    pub title: Option<TitlePattern>,
+    /// Group name ("Russian") or short label ("RU") of the layout to use
+    pub layout: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TitlePattern(pub Regex);
+
+/// Color written as "#RRGGBB" or, with alpha, "#RRGGBBAA" in the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Default for FontSection {
+    fn default() -> Self {
+        FontSection {
+            family: "DejaVu Sans".to_string(),
+            fallbacks: vec![
+                "Liberation Sans".to_string(),
+                "Noto Sans".to_string(),
+                "sans-serif".to_string(),
+            ],
+            path: None,
+            size: 16.0,
+        }
+    }
+}
+
+impl Default for IconSection {
+    fn default() -> Self {
+        IconSection {
+            size: 24,
+            background: Color { r: 35, g: 35, b: 35, a: 255 },
+            foreground: Color { r: 255, g: 255, b: 255, a: 255 },
+            background_mode: BackgroundMode::Argb,
+            locked_style: GroupStyle::Filled,
+            temporary_style: GroupStyle::Outlined,
+        }
+    }
+}
+
+impl IconSection {
+    pub fn style(&self, temporary: bool) -> GroupStyle {
+        if temporary { self.temporary_style } else { self.locked_style }
+    }
+}
+
+impl Default for IndicatorSection {
+    fn default() -> Self {
+        IndicatorSection {
+            caps_lock: true,
+            num_lock: false,
+            scroll_lock: false,
+            color: Color { r: 255, g: 176, b: 0, a: 255 },
+        }
+    }
+}
+
+impl Default for LabelSection {
+    fn default() -> Self {
+        LabelSection {
+            template: "{code}".to_string(),
+            overrides: HashMap::new(),
+            badges: true,
+        }
+    }
+}
+
+impl TraySection {
+    pub fn timeout(&self) -> Option<Duration> {
+        self.wait_timeout.map(Duration::from_secs)
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value
+            .strip_prefix('#')
+            .filter(|h| (h.len() == 6 || h.len() == 8) && h.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| format!("invalid color '{}', expected \"#RRGGBB\" or \"#RRGGBBAA\"", value))?;
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        let a = if hex.len() == 8 { channel(6) } else { 255 };
+        Ok(Color { r: channel(0), g: channel(2), b: channel(4), a })
+    }
+}
+
+impl TryFrom<String> for TitlePattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Regex::new(&value)
+            .map(TitlePattern)
+            .map_err(|e| format!("invalid title regex '{}': {}", value, e))
+    }
+}
+
+impl Color {
+    pub fn rgb(self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    pub fn rgba(self) -> [u8; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+}
+
+impl Config {
+    /// $XDG_CONFIG_HOME/psa-kb-switcher/config.toml (or ~/.config/... when unset)
+    pub fn path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    /// Loads the config file, or returns the defaults if it does not exist.
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        let Some(path) = Config::path() else {
+            return Ok(Config::default());
+        };
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("ERROR: Cannot read config '{}': {}", path.display(), e).into()),
+        };
+
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| format!("ERROR: Invalid config '{}': {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| format!("ERROR: Invalid config '{}': {}", path.display(), e))?;
+
+        println!("Loaded config from {}", path.display());
+        Ok(config)
+    }
+
+    /// Settings that are only read at startup keep their running values in
+    /// a reloaded `new` config; returns the ones that were changed there
+    pub fn keep_startup_settings(&self, new: &mut Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if new.icon.background_mode != self.icon.background_mode {
+            changed.push("icon.background_mode");
+            new.icon.background_mode = self.icon.background_mode;
+        }
+        if new.tray.wait_timeout != self.tray.wait_timeout {
+            changed.push("tray.wait_timeout");
+            new.tray.wait_timeout = self.tray.wait_timeout;
+        }
+        if new.tray.fallback != self.tray.fallback {
+            changed.push("tray.fallback");
+            new.tray.fallback = self.tray.fallback;
+        }
+        if new.floating.click_through != self.floating.click_through {
+            changed.push("floating.click_through");
+            new.floating.click_through = self.floating.click_through;
+        }
+        changed
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(8..=512).contains(&self.icon.size) {
+            return Err(format!("icon.size = {} is out of range (8..=512)", self.icon.size));
+        }
+        if !(self.font.size.is_finite() && self.font.size > 0.0) {
+            return Err(format!("font.size = {} must be a positive number", self.font.size));
+        }
+        if self.font.family.trim().is_empty() {
+            return Err("font.family must not be empty".to_string());
+        }
+        if let Some(i) = self.font.fallbacks.iter().position(|f| f.trim().is_empty()) {
+            return Err(format!("font.fallbacks[{}] must not be empty", i));
+        }
+        if self.font.path.as_ref().is_some_and(|p| p.as_os_str().is_empty()) {
+            return Err("font.path must not be empty".to_string());
+        }
+        if self.labels.template.trim().is_empty() {
+            return Err("labels.template must not be empty".to_string());
+        }
+        if let Some((key, _)) = self.labels.overrides.iter().find(|(_, label)| label.trim().is_empty()) {
+            return Err(format!("labels.overrides.\"{}\" must not be empty", key));
+        }
+        // Overrides are looked up case-insensitively
+        let mut keys: Vec<String> = self.labels.overrides.keys().map(|k| k.to_ascii_lowercase()).collect();
+        keys.sort();
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!("labels.overrides has \"{}\" more than once (keys ignore case)", pair[0]));
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.class.is_none() && rule.title.is_none() {
+                return Err(format!("rules[{}] needs a `class` or a `title` to match", i));
+            }
+            if rule.layout.trim().is_empty() {
+                return Err(format!("rules[{}].layout must not be empty", i));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Polls the config file and calls `on_change` whenever it is created,
+/// modified or removed. Polling (rather than inotify) also catches editors
+/// that replace the file instead of writing it in place.
+pub fn watch(path: PathBuf, on_change: impl Fn() + Send + 'static) {
+    let stamp = |path: &PathBuf| -> Option<(SystemTime, u64)> {
+        let meta = std::fs::metadata(path).ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    };
+
+    thread::spawn(move || {
+        let mut last = stamp(&path);
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let current = stamp(&path);
+            if current != last {
+                last = current;
+                on_change();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(Color::try_from("#ff8000".to_string()), Ok(Color { r: 255, g: 128, b: 0, a: 255 }));
+        assert_eq!(Color::try_from("#FF800040".to_string()), Ok(Color { r: 255, g: 128, b: 0, a: 64 }));
+        for bad in ["ff8000", "#ff800", "#ff80000", "#gg8000", "#ff8000ff00", "", "#"] {
+            assert!(Color::try_from(bad.to_string()).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn empty_file_is_the_defaults() {
+        let config = parse("").unwrap();
+        assert_eq!(config.icon.size, 24);
+        assert_eq!(config.font.family, "DejaVu Sans");
+        assert_eq!(config.labels.template, "{code}");
+    }
+
+    #[test]
+    fn partial_sections_keep_other_defaults() {
+        let config = parse("[icon]\nsize = 32\nbackground = \"#00000080\"\n").unwrap();
+        assert_eq!(config.icon.size, 32);
+        assert_eq!(config.icon.background.a, 0x80);
+        assert_eq!(config.icon.foreground, Color { r: 255, g: 255, b: 255, a: 255 });
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        assert!(parse("[icon]\nsizes = 32\n").is_err());
+        assert!(parse("[icon]\nbackground = \"red\"\n").is_err());
+        assert!(parse("[icon]\nsize = 4\n").is_err());
+        assert!(parse("[icon]\nsize = 1000\n").is_err());
+        assert!(parse("[font]\nsize = 0.0\n").is_err());
+        assert!(parse("[font]\nsize = nan\n").is_err());
+        assert!(parse("[font]\nfamily = \" \"\n").is_err());
+        assert!(parse("[font]\nfallbacks = [\"Noto Sans\", \"\"]\n").is_err());
+        assert!(parse("[font]\npath = \"\"\n").is_err());
+        assert!(parse("[labels]\ntemplate = \"\"\n").is_err());
+        assert!(parse("[labels.overrides]\nus = \" \"\n").is_err());
+        assert!(parse("[labels.overrides]\nus = \"EN\"\nUS = \"US\"\n").is_err());
+    }
+
+    #[test]
+    fn tray_timeout() {
+        assert_eq!(parse("").unwrap().tray.timeout(), None);
+        assert_eq!(parse("[tray]\nwait_timeout = 5\n").unwrap().tray.timeout(), Some(Duration::from_secs(5)));
+        assert!(parse("[tray]\ndock_retries = 10\n").is_err());
+    }
+
+    #[test]
+    fn rules() {
+        let config = parse("[[rules]]\nclass = \"firefox\"\ntitle = \"^Mail\"\nlayout = \"EN\"\n").unwrap();
+        assert_eq!(config.rules.len(), 1);
+        assert!(config.rules[0].title.as_ref().unwrap().0.is_match("Mail - Inbox"));
+
+        assert!(parse("[[rules]]\nlayout = \"EN\"\n").is_err());
+        assert!(parse("[[rules]]\nclass = \"firefox\"\nlayout = \"\"\n").is_err());
+        assert!(parse("[[rules]]\ntitle = \"(\"\nlayout = \"EN\"\n").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::shape::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{
+    self, ButtonPressEvent, ConfigureWindowAux, ConnectionExt as _, MotionNotifyEvent, StackMode,
+};
+
+const STATE_DIR: &str = "psa-kb-switcher";
+const STATE_FILE: &str = "position";
+/// Distance from the screen corner when no position was saved yet
+const MARGIN: i16 = 8;
+/// How far the pointer has to move before a click becomes a drag
+const DRAG_THRESHOLD: i16 = 4;
+
+/// The icon shown as a small always-on-top window, for setups without a
+/// system tray. Drag it with the left button; the position is kept in
+/// $XDG_STATE_HOME/psa-kb-switcher/position.
+pub struct Floating {
+    position: (i16, i16),
+    click_through: bool,
+    drag: Option<Drag>,
+}
+
+struct Drag {
+    /// Pointer position (root coordinates) when the button went down
+    pointer: (i16, i16),
+    /// Window position at that moment
+    window: (i16, i16),
+    moved: bool,
+}
+
+impl Floating {
+    /// Moves the (not docked) icon window to the root window, at the saved
+    /// position, and maps it
+    pub fn show(
+        conn: &impl Connection,
+        screen: &xproto::Screen,
+        win: xproto::Window,
+        size: u16,
+        click_through: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let max_x = screen.width_in_pixels.saturating_sub(size) as i16;
+        let max_y = screen.height_in_pixels.saturating_sub(size) as i16;
+        let (x, y) = load_position().unwrap_or((max_x - MARGIN, MARGIN));
+        let position = (x.clamp(0, max_x), y.clamp(0, max_y));
+
+        // Also takes it out of a tray that went away
+        conn.reparent_window(win, screen.root, position.0, position.1)?;
+        conn.configure_window(
+            win,
+            &ConfigureWindowAux::new()
+                .width(size as u32)
+                .height(size as u32)
+                .stack_mode(StackMode::ABOVE),
+        )?;
+        if click_through {
+            // An empty input shape lets every click through
+            conn.shape_rectangles(
+                shape::SO::SET,
+                shape::SK::INPUT,
+                xproto::ClipOrdering::UNSORTED,
+                win,
+                0,
+                0,
+                &[],
+            )?;
+        }
+        conn.map_window(win)?;
+        conn.flush()?;
+        println!("Showing the icon as a floating window at {:?}", position);
+        Ok(Floating { position, click_through, drag: None })
+    }
+
+    /// Turns the window back into a plain icon window, before docking it
+    pub fn leave(self, conn: &impl Connection, win: xproto::Window) -> Result<(), Box<dyn Error>> {
+        if self.click_through {
+            conn.shape_mask(shape::SO::SET, shape::SK::INPUT, win, 0, 0, x11rb::NONE)?;
+        }
+        conn.unmap_window(win)?;
+        Ok(())
+    }
+
+    /// Keeps the widget above other windows. It is override-redirect, so no
+    /// window manager does that for us.
+    pub fn raise(&self, conn: &impl Connection, win: xproto::Window) -> Result<(), Box<dyn Error>> {
+        conn.configure_window(win, &ConfigureWindowAux::new().stack_mode(StackMode::ABOVE))?;
+        conn.flush()?;
+        Ok(())
+    }
+
+    pub fn press(&mut self, e: &ButtonPressEvent) {
+        self.drag = Some(Drag {
+            pointer: (e.root_x, e.root_y),
+            window: self.position,
+            moved: false,
+        });
+    }
+
+    pub fn motion(&mut self, conn: &impl Connection, win: xproto::Window, e: &MotionNotifyEvent) -> Result<(), Box<dyn Error>> {
+        let Some(drag) = &mut self.drag else {
+            return Ok(());
+        };
+        let (dx, dy) = (e.root_x - drag.pointer.0, e.root_y - drag.pointer.1);
+        if !drag.moved && dx.abs() < DRAG_THRESHOLD && dy.abs() < DRAG_THRESHOLD {
+            return Ok(());
+        }
+        drag.moved = true;
+        self.position = (drag.window.0 + dx, drag.window.1 + dy);
+        conn.configure_window(
+            win,
+            &ConfigureWindowAux::new().x(self.position.0 as i32).y(self.position.1 as i32),
+        )?;
+        conn.flush()?;
+        Ok(())
+    }
+
+    /// Ends a press; returns true if it was a click rather than a drag
+    pub fn release(&mut self) -> bool {
+        match self.drag.take() {
+            Some(drag) if drag.moved => {
+                if let Err(e) = save_position(self.position) {
+                    eprintln!("Could not save the widget position: {}", e);
+                }
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+fn state_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+    Some(base.join(STATE_DIR).join(STATE_FILE))
+}
+
+/// "x y" as written by `save_position`
+fn load_position() -> Option<(i16, i16)> {
+    let text = std::fs::read_to_string(state_path()?).ok()?;
+    let mut parts = text.split_whitespace().map(|p| p.parse::<i16>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(x)), Some(Ok(y))) => Some((x, y)),
+        _ => None,
+    }
+}
+
+fn save_position((x, y): (i16, i16)) -> Result<(), Box<dyn Error>> {
+    let path = state_path().ok_or("ERROR: Neither XDG_STATE_HOME nor HOME is set")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, format!("{} {}\n", x, y))?;
+    Ok(())
+}
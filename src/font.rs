@@ -0,0 +1,125 @@
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use ab_glyph::{FontArc, FontRef, FontVec};
+use fontconfig_sys::constants::{FC_FAMILY, FC_FILE, FC_INDEX};
+use fontconfig_sys::statics::LIB_RESULT;
+use fontconfig_sys::{FcMatchPattern, FcPattern, FcResultMatch};
+
+use crate::config::FontSection;
+
+/// fontconfig aliases that resolve to whatever the system prefers,
+/// so the matched family is never expected to equal the requested one.
+const GENERIC_FAMILIES: &[&str] = &["sans-serif", "sans", "serif", "monospace", "mono", "system-ui"];
+
+/// Latin + Cyrillic subset of DejaVu Sans, see assets/LICENSE-fallback-sans.txt
+static FALLBACK_FONT: &[u8] = include_bytes!("../assets/fallback-sans.ttf");
+
+/// A font file picked by fontconfig for a pattern like "DejaVu Sans:bold"
+struct FontMatch {
+    file: PathBuf,
+    index: u32,
+    families: Vec<String>,
+}
+
+/// Loads the configured font, or the embedded one (with a warning) when
+/// nothing configured can be used, so the indicator always shows up.
+pub fn load_font(section: &FontSection) -> FontArc {
+    match load_configured_font(section) {
+        Ok(font) => font,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("WARNING: Falling back to the embedded font");
+            let font = FontRef::try_from_slice(FALLBACK_FONT).expect("embedded font is valid");
+            FontArc::new(font)
+        }
+    }
+}
+
+/// An explicit `path` wins, otherwise `family` and then each of `fallbacks`
+/// is resolved through fontconfig in order.
+fn load_configured_font(section: &FontSection) -> Result<FontArc, Box<dyn Error>> {
+    if let Some(path) = &section.path {
+        return load_file(path, 0);
+    }
+
+    let mut errors = Vec::new();
+    for pattern in std::iter::once(&section.family).chain(&section.fallbacks) {
+        match resolve(pattern).and_then(|m| load_file(&m.file, m.index).map(|f| (m, f))) {
+            Ok((m, font)) => {
+                println!("Using font '{}' ({}) for pattern '{}'", m.families.join(", "), m.file.display(), pattern);
+                return Ok(font);
+            }
+            Err(e) => errors.push(format!("'{}': {}", pattern, e)),
+        }
+    }
+
+    Err(format!("ERROR: No usable font found, tried {}", errors.join("; ")).into())
+}
+
+fn load_file(path: &Path, index: u32) -> Result<FontArc, Box<dyn Error>> {
+    let font_data = std::fs::read(path)
+        .map_err(|_| format!("ERROR: Font not found at '{}'", path.display()))?;
+    FontVec::try_from_vec_and_index(font_data, index)
+        .map(FontArc::new)
+        .map_err(|e| format!("ERROR: Cannot parse font '{}': {}", path.display(), e).into())
+}
+
+/// Runs the usual fontconfig match (substitute, default-substitute, FcFontMatch)
+/// and rejects the result if fontconfig silently picked a different family.
+fn resolve(pattern: &str) -> Result<FontMatch, Box<dyn Error>> {
+    let fc = LIB_RESULT
+        .as_ref()
+        .map_err(|e| format!("fontconfig is not available: {}", e))?;
+    let c_pattern = CString::new(pattern).map_err(|_| "pattern contains a NUL byte")?;
+
+    // SAFETY: every pattern created here is destroyed before returning and
+    // strings are copied out before their owning pattern goes away.
+    unsafe {
+        let pat = (fc.FcNameParse)(c_pattern.as_ptr() as *const u8);
+        if pat.is_null() {
+            return Err("invalid fontconfig pattern".into());
+        }
+        let requested = get_strings(fc, pat, FC_FAMILY).into_iter().next();
+
+        (fc.FcConfigSubstitute)(ptr::null_mut(), pat, FcMatchPattern);
+        (fc.FcDefaultSubstitute)(pat);
+        let mut result = 0;
+        let matched = (fc.FcFontMatch)(ptr::null_mut(), pat, &mut result);
+        (fc.FcPatternDestroy)(pat);
+        if matched.is_null() {
+            return Err("no match".into());
+        }
+
+        let file = get_strings(fc, matched, FC_FILE).into_iter().next();
+        let mut index: c_int = 0;
+        (fc.FcPatternGetInteger)(matched, FC_INDEX.as_ptr(), 0, &mut index);
+        let families = get_strings(fc, matched, FC_FAMILY);
+        (fc.FcPatternDestroy)(matched);
+
+        let file = file.ok_or("match has no file")?;
+        if let Some(requested) = requested {
+            let generic = GENERIC_FAMILIES.iter().any(|g| g.eq_ignore_ascii_case(&requested));
+            if !generic && !families.iter().any(|f| f.eq_ignore_ascii_case(&requested)) {
+                return Err(format!("not installed (fontconfig offered '{}')", families.join(", ")).into());
+            }
+        }
+
+        Ok(FontMatch { file: PathBuf::from(file), index: index.max(0) as u32, families })
+    }
+}
+
+unsafe fn get_strings(fc: &fontconfig_sys::Fc, pat: *mut FcPattern, object: &CStr) -> Vec<String> {
+    let mut res = Vec::new();
+    for n in 0.. {
+        let mut value: *mut u8 = ptr::null_mut();
+        if (fc.FcPatternGetString)(pat, object.as_ptr(), n, &mut value) != FcResultMatch {
+            break;
+        }
+        res.push(CStr::from_ptr(value as *const _).to_string_lossy().into_owned());
+    }
+    res
+}
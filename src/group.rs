@@ -0,0 +1,66 @@
+use std::error::Error;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xkb::{self, ConnectionExt as _};
+
+/// Locks the given XKB group. The icon itself is updated later,
+/// when the server answers with XkbStateNotify.
+pub fn lock_group(conn: &impl Connection, group: u8) -> Result<(), Box<dyn Error>> {
+    conn.xkb_latch_lock_state(
+        xkb::ID::USE_CORE_KBD.into(),
+        0u8.into(),
+        0u8.into(),
+        true,
+        group.into(),
+        0u8.into(),
+        false,
+        0,
+    )?;
+    conn.flush()?;
+    Ok(())
+}
+
+/// The group after `current`, wrapping around after the last one
+pub fn next_group(current: u8, count: usize) -> u8 {
+    let count = count.max(1);
+    ((current as usize + 1) % count) as u8
+}
+
+/// The group before `current`, wrapping around to the last one
+pub fn prev_group(current: u8, count: usize) -> u8 {
+    let count = count.max(1);
+    ((current as usize + count - 1) % count) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_wraps_around() {
+        assert_eq!(next_group(0, 3), 1);
+        assert_eq!(next_group(1, 3), 2);
+        assert_eq!(next_group(2, 3), 0);
+        assert_eq!(next_group(0, 1), 0);
+        // No group names at all
+        assert_eq!(next_group(0, 0), 0);
+    }
+
+    #[test]
+    fn prev_wraps_around() {
+        assert_eq!(prev_group(2, 3), 1);
+        assert_eq!(prev_group(1, 3), 0);
+        assert_eq!(prev_group(0, 3), 2);
+        assert_eq!(prev_group(0, 1), 0);
+        assert_eq!(prev_group(0, 0), 0);
+    }
+
+    #[test]
+    fn prev_undoes_next() {
+        for count in 1..5 {
+            for group in 0..count as u8 {
+                assert_eq!(prev_group(next_group(group, count), count), group);
+            }
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use std::error::Error;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xkb::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{self, AtomEnum, ConnectionExt as _};
+
+use crate::config::LabelSection;
+
+/// XKB layout (mostly ISO 3166 country codes) to ISO 639-1 language code
+const LANGUAGES: &[(&str, &str)] = &[
+    ("af", "fa"), ("al", "sq"), ("am", "hy"), ("ara", "ar"), ("at", "de"), ("au", "en"),
+    ("az", "az"), ("ba", "bs"), ("bd", "bn"), ("be", "fr"), ("bg", "bg"), ("br", "pt"),
+    ("by", "be"), ("ca", "fr"), ("ch", "de"), ("cn", "zh"), ("cz", "cs"), ("de", "de"),
+    ("dk", "da"), ("ee", "et"), ("epo", "eo"), ("es", "es"), ("fi", "fi"), ("fo", "fo"),
+    ("fr", "fr"), ("gb", "en"), ("ge", "ka"), ("gr", "el"), ("hr", "hr"), ("hu", "hu"),
+    ("ie", "ga"), ("il", "he"), ("in", "hi"), ("iq", "ar"), ("ir", "fa"), ("is", "is"),
+    ("it", "it"), ("jp", "ja"), ("ke", "sw"), ("kg", "ky"), ("kh", "km"), ("kr", "ko"),
+    ("kz", "kk"), ("la", "lo"), ("latam", "es"), ("lk", "si"), ("lt", "lt"), ("lv", "lv"),
+    ("ma", "ar"), ("me", "sr"), ("mk", "mk"), ("mm", "my"), ("mn", "mn"), ("mt", "mt"),
+    ("ng", "en"), ("nl", "nl"), ("no", "no"), ("np", "ne"), ("nz", "en"), ("ph", "tl"),
+    ("pk", "ur"), ("pl", "pl"), ("pt", "pt"), ("ro", "ro"), ("rs", "sr"), ("ru", "ru"),
+    ("se", "sv"), ("si", "sl"), ("sk", "sk"), ("sy", "ar"), ("th", "th"), ("tj", "tg"),
+    ("tm", "tk"), ("tr", "tr"), ("tw", "zh"), ("tz", "sw"), ("ua", "uk"), ("us", "en"),
+    ("uz", "uz"), ("vn", "vi"), ("za", "en"),
+];
+
+/// First word of XKB group names ("English (US)") to ISO 639-1, for servers
+/// without _XKB_RULES_NAMES
+const LANGUAGE_NAMES: &[(&str, &str)] = &[
+    ("albanian", "sq"), ("arabic", "ar"), ("armenian", "hy"), ("belarusian", "be"),
+    ("bulgarian", "bg"), ("chinese", "zh"), ("croatian", "hr"), ("czech", "cs"),
+    ("danish", "da"), ("dutch", "nl"), ("english", "en"), ("esperanto", "eo"),
+    ("estonian", "et"), ("finnish", "fi"), ("french", "fr"), ("georgian", "ka"),
+    ("german", "de"), ("greek", "el"), ("hebrew", "he"), ("hindi", "hi"),
+    ("hungarian", "hu"), ("icelandic", "is"), ("irish", "ga"), ("italian", "it"),
+    ("japanese", "ja"), ("kazakh", "kk"), ("korean", "ko"), ("latvian", "lv"),
+    ("lithuanian", "lt"), ("macedonian", "mk"), ("norwegian", "no"), ("persian", "fa"),
+    ("polish", "pl"), ("portuguese", "pt"), ("romanian", "ro"), ("russian", "ru"),
+    ("serbian", "sr"), ("slovak", "sk"), ("slovenian", "sl"), ("spanish", "es"),
+    ("swedish", "sv"), ("thai", "th"), ("turkish", "tr"), ("ukrainian", "uk"),
+    ("vietnamese", "vi"),
+];
+
+/// Everything known about the keyboard's groups, in group order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layouts {
+    /// XKB group names ("English (US)"); also key the icon cache
+    pub names: Vec<String>,
+    /// ISO 639-1 code of each group's language ("EN"), or a guess from
+    /// the group name
+    pub codes: Vec<String>,
+    /// Per group from _XKB_RULES_NAMES; empty if that property is unusable
+    pub symbols: Vec<Symbol>,
+    /// Text drawn on the icon, from the `[labels]` templates
+    pub labels: Vec<String>,
+    /// Small marks telling apart groups with the same label; empty strings
+    /// for the rest
+    pub badges: Vec<String>,
+}
+
+/// XKB layout and variant of a group, e.g. "us" and "dvorak"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub layout: String,
+    /// Empty for the default variant
+    pub variant: String,
+}
+
+impl Layouts {
+    pub fn load(conn: &impl Connection, root: xproto::Window, section: &LabelSection) -> Result<Self, Box<dyn Error>> {
+        let mut layouts = Layouts::new(get_group_names(conn)?, get_rules_layouts(conn, root)?);
+        layouts.relabel(section);
+        Ok(layouts)
+    }
+
+    /// Symbols that do not line up with the groups are ignored, e.g. while
+    /// setxkbmap has updated only one of the two
+    pub fn new(names: Vec<String>, symbols: Option<Vec<Symbol>>) -> Self {
+        let symbols = symbols
+            .filter(|symbols| symbols.len() == names.len())
+            .unwrap_or_default();
+        let codes = if symbols.is_empty() {
+            names.iter().map(|name| shorten_name(name)).collect()
+        } else {
+            symbols.iter().map(|symbol| label_for(&symbol.layout)).collect()
+        };
+        Layouts { names, codes, symbols, labels: Vec::new(), badges: Vec::new() }
+    }
+
+    /// Fills `labels` from the templates, e.g. after a config reload
+    pub fn relabel(&mut self, section: &LabelSection) {
+        self.labels = (0..self.names.len())
+            .map(|i| {
+                let symbol = self.symbols.get(i);
+                let layout = symbol.map_or("", |s| s.layout.as_str());
+                let variant = symbol.map_or("", |s| s.variant.as_str());
+
+                // The most specific key wins
+                let keys = [format!("{}({})", layout, variant), self.names[i].clone(), layout.to_string()];
+                let template = keys
+                    .iter()
+                    .filter(|key| !key.is_empty() && key.as_str() != "()")
+                    .find_map(|key| {
+                        section.overrides.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v)
+                    })
+                    .unwrap_or(&section.template);
+
+                // Placeholders that expand to nothing are marked for `tidy`
+                let value = |value: &str| if value.is_empty() { EMPTY.to_string() } else { value.to_string() };
+                let text = template
+                    .replace("{code}", &value(&self.codes[i]))
+                    .replace("{layout}", &value(layout))
+                    .replace("{variant}", &value(variant))
+                    .replace("{name}", &value(&self.names[i]));
+                let text = tidy(&text);
+                if text.is_empty() { self.codes[i].clone() } else { text }
+            })
+            .collect();
+
+        self.badges = (0..self.names.len())
+            .map(|i| {
+                let shared = self.labels.iter().enumerate().any(|(j, label)| j != i && *label == self.labels[i]);
+                if !section.badges || !shared {
+                    return String::new();
+                }
+                match self.symbols.get(i) {
+                    Some(symbol) => symbol.variant.chars().take(2).collect::<String>().to_lowercase(),
+                    // Nothing better to go on than the group number
+                    None => (i + 1).to_string(),
+                }
+            })
+            .collect();
+    }
+
+    /// Warns about groups whose icons would still be identical
+    pub fn warn_collisions(&self) {
+        for i in 0..self.names.len() {
+            for j in i + 1..self.names.len() {
+                if self.labels[i] == self.labels[j] && self.badges[i] == self.badges[j] {
+                    eprintln!(
+                        "WARNING: '{}' and '{}' have the same icon ({:?}); tell them apart with [labels] overrides",
+                        self.names[i], self.names[j], self.labels[i]
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Stands in for a placeholder that expanded to nothing
+const EMPTY: char = '\0';
+
+/// Drops the `EMPTY` marks along with the separators they leave behind
+/// ("EN·" for "{code}·{variant}"), then empty lines. Text written in the
+/// template itself, such as an override "EN.", is kept as it is.
+fn tidy(text: &str) -> String {
+    const SEPARATORS: &[char] = &['·', '-', '_', '/', '|', ':', '.', ','];
+    let is_separator = |c: char| c.is_whitespace() || SEPARATORS.contains(&c);
+
+    text.lines()
+        .map(|line| {
+            let mut line = line.to_string();
+            while let Some(i) = line.find(EMPTY) {
+                let before = &line[..i];
+                let after = line[i + EMPTY.len_utf8()..].trim_start_matches(is_separator);
+                // The separator after the placeholder goes; at the end of
+                // the line, the one before it
+                line = if after.is_empty() {
+                    before.trim_end_matches(is_separator).to_string()
+                } else {
+                    format!("{}{}", before, after)
+                };
+            }
+            line
+        })
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// ISO 639-1 code of the layout's language, upper-cased
+fn label_for(layout: &str) -> String {
+    match LANGUAGES.iter().find(|(xkb, _)| *xkb == layout) {
+        Some((_, language)) => language.to_uppercase(),
+        None => layout.chars().take(2).collect::<String>().to_uppercase(),
+    }
+}
+
+/// Guess from the group name, for servers without _XKB_RULES_NAMES: the
+/// language of "Russian" or "English (US)", or a bare layout such as "us"
+fn shorten_name(name: &str) -> String {
+    let lower = name.trim().to_lowercase();
+    if let Some((_, language)) = LANGUAGES.iter().find(|(xkb, _)| *xkb == lower) {
+        return language.to_uppercase();
+    }
+    let first_word = lower.split(|c: char| !c.is_alphabetic()).next().unwrap_or_default();
+    match LANGUAGE_NAMES.iter().find(|(word, _)| *word == first_word) {
+        Some((_, language)) => language.to_uppercase(),
+        None => name.trim().chars().take(2).collect::<String>().to_uppercase(),
+    }
+}
+
+fn get_group_names(conn: &impl Connection) -> Result<Vec<String>, Box<dyn Error>> {
+    let names = conn.xkb_get_names(xkb::ID::USE_CORE_KBD.into(), xkb::NameDetail::GROUP_NAMES)?.reply()?;
+    let mut res = Vec::new();
+    if let Some(groups) = names.value_list.groups {
+        for atom in groups {
+            if atom == 0 { break; }
+            let name = String::from_utf8(conn.get_atom_name(atom)?.reply()?.name)?;
+            res.push(name);
+        }
+    }
+    if res.is_empty() { res.push("US".to_string()); }
+    Ok(res)
+}
+
+/// Layouts and variants from the root's _XKB_RULES_NAMES, which holds
+/// "rules\0model\0layout\0variant\0options\0", e.g. layout "us,ru" and
+/// variant "dvorak,"
+fn get_rules_layouts(conn: &impl Connection, root: xproto::Window) -> Result<Option<Vec<Symbol>>, Box<dyn Error>> {
+    let atom = conn.intern_atom(false, b"_XKB_RULES_NAMES")?.reply()?.atom;
+    let reply = conn.get_property(false, root, atom, AtomEnum::STRING, 0, 1024)?.reply()?;
+    Ok(parse_rules_names(&reply.value))
+}
+
+fn parse_rules_names(value: &[u8]) -> Option<Vec<Symbol>> {
+    let value = String::from_utf8_lossy(value);
+    let mut fields = value.split('\0');
+
+    let layout = fields.nth(2).filter(|l| !l.is_empty())?;
+    let variants: Vec<&str> = fields.next().unwrap_or_default().split(',').collect();
+    Some(
+        layout
+            .split(',')
+            .enumerate()
+            .map(|(i, layout)| {
+                let variant = variants.get(i).copied().unwrap_or_default();
+                Symbol {
+                    layout: layout.trim().to_string(),
+                    variant: variant.trim().to_string(),
+                }
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(layout: &str, variant: &str) -> Symbol {
+        Symbol { layout: layout.to_string(), variant: variant.to_string() }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn labels_are_languages() {
+        assert_eq!(label_for("us"), "EN");
+        assert_eq!(label_for("gb"), "EN");
+        assert_eq!(label_for("ua"), "UK");
+        assert_eq!(label_for("by"), "BE");
+        assert_eq!(label_for("ch"), "DE");
+        // Unknown layouts keep their first letters
+        assert_eq!(label_for("xyz"), "XY");
+    }
+
+    #[test]
+    fn names_agree_with_layouts() {
+        assert_eq!(shorten_name("English (US)"), "EN");
+        assert_eq!(shorten_name("Russian"), "RU");
+        assert_eq!(shorten_name("Ukrainian"), label_for("ua"));
+        assert_eq!(shorten_name("Belarusian"), label_for("by"));
+        assert_eq!(shorten_name("Russian (phonetic)"), "RU");
+        assert_eq!(shorten_name("US"), label_for("us"));
+        assert_eq!(shorten_name("ua"), label_for("ua"));
+        assert_eq!(shorten_name("Klingon"), "KL");
+    }
+
+    #[test]
+    fn rules_names() {
+        let symbols = parse_rules_names(b"evdev\0pc105\0us,ru,ua\0dvorak,,\0grp:alt_shift_toggle\0");
+        assert_eq!(symbols, Some(vec![symbol("us", "dvorak"), symbol("ru", ""), symbol("ua", "")]));
+    }
+
+    #[test]
+    fn rules_names_with_fewer_variants() {
+        let symbols = parse_rules_names(b"evdev\0pc105\0us, ru ,de\0,phonetic\0");
+        assert_eq!(symbols, Some(vec![symbol("us", ""), symbol("ru", "phonetic"), symbol("de", "")]));
+
+        let symbols = parse_rules_names(b"evdev\0pc105\0us,ru");
+        assert_eq!(symbols, Some(vec![symbol("us", ""), symbol("ru", "")]));
+    }
+
+    #[test]
+    fn rules_names_without_layouts() {
+        assert_eq!(parse_rules_names(b""), None);
+        assert_eq!(parse_rules_names(b"evdev\0pc105\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn codes_from_symbols() {
+        let layouts = Layouts::new(names(&["English (Dvorak)", "Ukrainian"]), Some(vec![symbol("us", "dvorak"), symbol("ua", "")]));
+        assert_eq!(layouts.codes, ["EN", "UK"]);
+        assert_eq!(layouts.symbols.len(), 2);
+    }
+
+    #[test]
+    fn mismatched_symbols_fall_back_to_names() {
+        let layouts = Layouts::new(names(&["English (US)", "Russian", "Ukrainian"]), Some(vec![symbol("us", ""), symbol("ru", "")]));
+        assert!(layouts.symbols.is_empty());
+        assert_eq!(layouts.codes, ["EN", "RU", "UK"]);
+
+        let layouts = Layouts::new(names(&["English (US)"]), None);
+        assert_eq!(layouts.codes, ["EN"]);
+    }
+
+    fn labels(template: &str, overrides: &[(&str, &str)]) -> Vec<String> {
+        let section = LabelSection {
+            template: template.to_string(),
+            overrides: overrides.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Default::default()
+        };
+        let mut layouts = Layouts::new(
+            names(&["English (US)", "English (Dvorak)", "Russian"]),
+            Some(vec![symbol("us", ""), symbol("us", "dvorak"), symbol("ru", "")]),
+        );
+        layouts.relabel(&section);
+        layouts.labels
+    }
+
+    #[test]
+    fn tidy_drops_separators_of_empty_placeholders() {
+        assert_eq!(tidy("EN·\0"), "EN");
+        assert_eq!(tidy("\0 - EN"), "EN");
+        assert_eq!(tidy("EN/\0/RU"), "EN/RU");
+        assert_eq!(tidy("EN\n\0"), "EN");
+        assert_eq!(tidy("\0·\0"), "");
+    }
+
+    #[test]
+    fn tidy_keeps_written_text() {
+        assert_eq!(tidy("EN."), "EN.");
+        assert_eq!(tidy("RU-"), "RU-");
+        assert_eq!(tidy("(EN)·\0"), "(EN)");
+    }
+
+    #[test]
+    fn code_and_variant() {
+        assert_eq!(labels("{code}", &[]), ["EN", "EN", "RU"]);
+        assert_eq!(labels("{code}·{variant}", &[]), ["EN", "EN·dvorak", "RU"]);
+        assert_eq!(labels("{layout}({variant})", &[]), ["us()", "us(dvorak)", "ru()"]);
+    }
+
+    #[test]
+    fn two_lines() {
+        assert_eq!(labels("{code}\n{variant}", &[]), ["EN", "EN\ndvorak", "RU"]);
+        assert_eq!(labels("{variant}\n{code}", &[]), ["EN", "dvorak\nEN", "RU"]);
+    }
+
+    #[test]
+    fn overrides_by_specificity() {
+        let overrides = [("us(dvorak)", "DV"), ("US", "US."), ("Russian", "RU-{variant}")];
+        assert_eq!(labels("{code}", &overrides), ["US.", "DV", "RU"]);
+        // Names work too, but "layout(variant)" comes first
+        let overrides = [("English (Dvorak)", "ED"), ("us(dvorak)", "DV")];
+        assert_eq!(labels("{code}", &overrides), ["EN", "DV", "RU"]);
+    }
+
+    #[test]
+    fn badges_for_shared_labels() {
+        let section = LabelSection::default();
+        let mut layouts = Layouts::new(
+            names(&["English (US)", "English (Dvorak)", "Russian", "Russian (phonetic)", "German"]),
+            Some(vec![symbol("us", ""), symbol("us", "dvorak"), symbol("ru", ""), symbol("ru", "phonetic"), symbol("de", "")]),
+        );
+        layouts.relabel(&section);
+        assert_eq!(layouts.badges, ["", "dv", "", "ph", ""]);
+
+        let section = LabelSection { badges: false, ..Default::default() };
+        layouts.relabel(&section);
+        assert_eq!(layouts.badges, ["", "", "", "", ""]);
+    }
+
+    #[test]
+    fn badges_without_symbols() {
+        let mut layouts = Layouts::new(names(&["English (US)", "English (UK)"]), None);
+        layouts.relabel(&LabelSection::default());
+        assert_eq!(layouts.badges, ["1", "2"]);
+    }
+}
@@ -0,0 +1,123 @@
+use std::error::Error;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xkb::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::ConnectionExt as _;
+
+use crate::config::IndicatorSection;
+
+/// Lock keys currently marked on the icon
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Locks {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+impl Locks {
+    /// Every combination the icon can show with the given settings
+    pub fn variants(section: &IndicatorSection) -> Vec<Locks> {
+        let options = |enabled: bool| if enabled { vec![false, true] } else { vec![false] };
+        let mut variants = Vec::new();
+        for caps in options(section.caps_lock) {
+            for num in options(section.num_lock) {
+                for scroll in options(section.scroll_lock) {
+                    variants.push(Locks { caps, num, scroll });
+                }
+            }
+        }
+        variants
+    }
+}
+
+/// Positions of the lock indicators in the XKB indicator state, looked up
+/// by name since the keymap decides the order
+pub struct LockIndicators {
+    caps: Option<u32>,
+    num: Option<u32>,
+    scroll: Option<u32>,
+}
+
+impl LockIndicators {
+    pub fn new(conn: &impl Connection) -> Result<Self, Box<dyn Error>> {
+        let names = conn
+            .xkb_get_names(xkb::ID::USE_CORE_KBD.into(), xkb::NameDetail::INDICATOR_NAMES)?
+            .reply()?;
+        let mut indicators = LockIndicators { caps: None, num: None, scroll: None };
+
+        // Names are listed for the set bits of `indicators`, in bit order
+        let atoms = names.value_list.indicator_names.unwrap_or_default();
+        let bits = (0..32).filter(|bit| names.indicators & (1 << bit) != 0);
+        for (bit, atom) in bits.zip(atoms) {
+            let name = conn.get_atom_name(atom)?.reply()?.name;
+            match name.as_slice() {
+                b"Caps Lock" => indicators.caps = Some(bit),
+                b"Num Lock" => indicators.num = Some(bit),
+                b"Scroll Lock" => indicators.scroll = Some(bit),
+                _ => {}
+            }
+        }
+        Ok(indicators)
+    }
+
+    /// The enabled locks that are on in `state`
+    pub fn locks(&self, state: u32, section: &IndicatorSection) -> Locks {
+        let on = |bit: Option<u32>| bit.is_some_and(|bit| state & (1 << bit) != 0);
+        Locks {
+            caps: section.caps_lock && on(self.caps),
+            num: section.num_lock && on(self.num),
+            scroll: section.scroll_lock && on(self.scroll),
+        }
+    }
+}
+
+pub fn get_indicator_state(conn: &impl Connection) -> Result<u32, Box<dyn Error>> {
+    Ok(conn.xkb_get_indicator_state(xkb::ID::USE_CORE_KBD.into())?.reply()?.state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(caps_lock: bool, num_lock: bool, scroll_lock: bool) -> IndicatorSection {
+        IndicatorSection { caps_lock, num_lock, scroll_lock, ..Default::default() }
+    }
+
+    #[test]
+    fn variants_cover_enabled_locks_only() {
+        assert_eq!(Locks::variants(&section(false, false, false)), [Locks::default()]);
+
+        let caps = Locks::variants(&section(true, false, false));
+        assert_eq!(caps, [Locks::default(), Locks { caps: true, ..Default::default() }]);
+
+        let all = Locks::variants(&section(true, true, true));
+        assert_eq!(all.len(), 8);
+        for (i, a) in all.iter().enumerate() {
+            assert!(!all[i + 1..].contains(a), "{:?} twice", a);
+        }
+
+        let num_scroll = Locks::variants(&section(false, true, true));
+        assert_eq!(num_scroll.len(), 4);
+        assert!(num_scroll.iter().all(|locks| !locks.caps));
+    }
+
+    #[test]
+    fn indicator_bits() {
+        // Caps Lock on bit 0, Num Lock on bit 1, no Scroll Lock indicator
+        let indicators = LockIndicators { caps: Some(0), num: Some(1), scroll: None };
+        let all = section(true, true, true);
+
+        assert_eq!(indicators.locks(0b00, &all), Locks::default());
+        assert_eq!(indicators.locks(0b01, &all), Locks { caps: true, ..Default::default() });
+        assert_eq!(indicators.locks(0b10, &all), Locks { num: true, ..Default::default() });
+        // Bits of other indicators are ignored
+        assert_eq!(indicators.locks(0b111100, &all), Locks::default());
+    }
+
+    #[test]
+    fn disabled_locks_stay_off() {
+        let indicators = LockIndicators { caps: Some(0), num: Some(1), scroll: Some(2) };
+        assert_eq!(indicators.locks(0b111, &section(true, false, false)), Locks { caps: true, ..Default::default() });
+        assert_eq!(indicators.locks(0b111, &section(false, false, false)), Locks::default());
+    }
+}
@@ -1,137 +1,427 @@
-use std::collections::HashMap;
+mod config;
+mod floating;
+mod font;
+mod group;
+mod layouts;
+mod locks;
+mod memory;
+mod popup;
+mod render;
+mod rules;
+mod tray;
+mod visual;
+mod xembed;
+
 use std::error::Error;
-use std::thread; // Added for pause functionality
-use std::time::Duration; // Added for pause functionality
 
-use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
-use image::{Rgba, RgbaImage};
+use ab_glyph::FontArc;
 use x11rb::connection::Connection;
 use x11rb::protocol::xkb::{self, ConnectionExt as _};
-use x11rb::protocol::xproto::{
-    self, ClientMessageEvent, ConnectionExt as _, CreateWindowAux, EventMask,
-    WindowClass,
-};
-
-// !!! FONT PATH !!!
-const FONT_PATH: &str = "/usr/share/fonts/TTF/DejaVuSans.ttf";
-const ICON_SIZE: u16 = 24;
+use x11rb::protocol::xproto::{self, ClientMessageEvent, ConnectionExt as _, EventMask};
+
+use config::{BackgroundMode, Config, TrayFallback};
+use floating::Floating;
+use layouts::Layouts;
+use locks::{LockIndicators, Locks};
+use memory::{Focus, LayoutMemory};
+use rules::RuleEngine;
+use popup::{Popup, PopupAction};
+use render::{IconCache, IconSize, IconVariant};
+use xembed::Xembed;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load()?;
+
     // 1. Connecting to X11
     let (conn, screen_num) = x11rb::connect(None)?;
     let screen = &conn.setup().roots[screen_num];
     let root_window = screen.root;
 
-    // Enable XKB extension (keyboard)
+    // Enable XKB extension (keyboard). Besides state changes, watch for a new
+    // keymap or group names (e.g. `setxkbmap -layout us,ru,de`) and for the
+    // lock indicators. MapNotify is only sent for the map parts selected
+    // here, `select_all` does not cover it.
     conn.xkb_use_extension(1, 0)?;
+    let map_parts = xkb::MapPart::KEY_TYPES | xkb::MapPart::KEY_SYMS | xkb::MapPart::MODIFIER_MAP;
     conn.xkb_select_events(
         xkb::ID::USE_CORE_KBD.into(),
         0u16.into(),
-        xkb::EventType::STATE_NOTIFY,
-        0u16.into(),
-        0u16.into(),
+        xkb::EventType::STATE_NOTIFY
+            | xkb::EventType::NEW_KEYBOARD_NOTIFY
+            | xkb::EventType::MAP_NOTIFY
+            | xkb::EventType::NAMES_NOTIFY
+            | xkb::EventType::INDICATOR_STATE_NOTIFY,
+        map_parts,
+        map_parts,
         &xkb::SelectEventsAux::default(),
     )?;
 
     // 2. Loading font
-    let layout_names = get_layout_names(&conn)?;
-    println!("Detected layouts: {:?}", layout_names);
-
-    let font_data = std::fs::read(FONT_PATH)
-        .map_err(|_| format!("ERROR: Font not found at '{}'", FONT_PATH))?;
-    let font = FontRef::try_from_slice(&font_data)?;
-
-    let mut icon_cache = HashMap::new();
-    for name in &layout_names {
-        let short = shorten_name(name);
-        let pixels = render_icon_bgra(&short, &font);
-        icon_cache.insert(name.clone(), pixels);
-    }
-
-    // 3. Creating window
-    let win_id = conn.generate_id()?;
-    let white_pixel = screen.white_pixel;
-
-    let win_aux = CreateWindowAux::new()
-        .background_pixel(white_pixel)
-        .override_redirect(1)
-        .event_mask(EventMask::EXPOSURE | EventMask::STRUCTURE_NOTIFY);
-
-    conn.create_window(
-        x11rb::COPY_FROM_PARENT as u8,
-        win_id,
+    let mut layouts = Layouts::load(&conn, root_window, &config.labels)?;
+    let xkb_rules_atom = conn.intern_atom(false, b"_XKB_RULES_NAMES")?.reply()?.atom;
+    println!("Detected layouts: {:?} {:?}", layouts.names, layouts.labels);
+    layouts.warn_collisions();
+
+    let mut font = font::load_font(&config.font);
+
+    // 3. Waiting for the tray. Root window events: MANAGER messages from a
+    // (re)started tray, and _NET_ACTIVE_WINDOW / _NET_CURRENT_DESKTOP
+    // changes for the layout memory
+    let tray_atom = tray::selection_atom(&conn, screen_num)?;
+    let manager_atom = conn.intern_atom(false, b"MANAGER")?.reply()?.atom;
+    conn.change_window_attributes(
         root_window,
-        0, 0, ICON_SIZE, ICON_SIZE,
-        0,
-        WindowClass::INPUT_OUTPUT,
-        x11rb::COPY_FROM_PARENT,
-        &win_aux,
+        &xproto::ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY),
     )?;
 
-    // 4. Docking (WITH CHANGES: RETRY ATTEMPTS)
-    let max_retries = 10; // 10 attempts
-    let mut docked = false;
-
-    println!("Attempting to dock into System Tray...");
-    for i in 1..=max_retries {
-        match dock_window_to_tray(&conn, screen_num, win_id) {
-            Ok(_) => {
-                docked = true;
-                println!("Successfully docked on attempt #{}", i);
-                break;
-            }
-            Err(_) => {
-                if i < max_retries {
-                    println!("Tray not found (attempt {}/{}), retrying in 500ms...", i, max_retries);
-                    thread::sleep(Duration::from_millis(500));
-                }
-            }
+    // The floating widget does not wait for a tray unless told to; one that
+    // starts later takes the icon over
+    let found = match (config.tray.fallback, config.tray.timeout()) {
+        (TrayFallback::Floating, None) => tray::find_manager(&conn, tray_atom)?,
+        (_, timeout) => tray::wait_for_manager(&conn, screen, tray_atom, manager_atom, timeout)?,
+    };
+    let mut manager = match found {
+        Some(manager) => {
+            println!("Found System Tray");
+            manager
         }
-    }
+        None if config.tray.fallback == TrayFallback::Floating => x11rb::NONE,
+        None => return Err("ERROR: No System Tray appeared in time. Is tint2/panel running?".into()),
+    };
 
-    if !docked {
-        return Err("Could not find System Tray after waiting. Is tint2/panel running?".into());
+    // 4. Creating window with the tray's visual (ARGB if it offers one) and
+    // docking, or showing it as a floating widget when there is no tray
+    let argb_visual = match manager {
+        x11rb::NONE => None,
+        manager => tray::argb_visual(&conn, screen, manager)?,
+    };
+    let background = tray::effective_background(
+        config.icon.background_mode,
+        argb_visual,
+        config.icon.background.a < 255,
+    );
+    let icon_events = EventMask::EXPOSURE
+        | EventMask::STRUCTURE_NOTIFY
+        | EventMask::BUTTON_PRESS
+        | EventMask::BUTTON_RELEASE
+        | EventMask::BUTTON1_MOTION
+        | EventMask::VISIBILITY_CHANGE;
+    let mut icon_window =
+        tray::create_icon_window(&conn, screen, background, argb_visual, config.icon.size, icon_events)?;
+    let mut win_id = icon_window.id;
+    println!("Icon background mode: {:?}", icon_window.background);
+    let mut xembed = Xembed::new(&conn)?;
+    xembed.setup_window(&conn, win_id)?;
+    let mut floating = None;
+    if manager == x11rb::NONE {
+        floating = Some(Floating::show(&conn, screen, win_id, config.icon.size, config.floating.click_through)?);
+    } else {
+        tray::dock(&conn, manager, win_id)?;
+        tray::watch_manager(&conn, manager)?;
     }
 
-    // Show window
-    conn.map_window(win_id)?;
+    let mut icon_size = IconSize::square(config.icon.size);
+    let mut icon_cache = IconCache::new(&conn, &icon_window)?;
+    fill_icon_cache(&conn, &mut icon_cache, &icon_window, &layouts, &font, &config, icon_size)?;
+
+    // The tray maps the window once it is embedded (XEMBED_MAPPED)
     conn.flush()?;
 
+    // Re-read the config whenever the file changes on disk
+    let reload_atom = conn.intern_atom(false, b"_PSA_KB_SWITCHER_RELOAD")?.reply()?.atom;
+    spawn_config_watcher(&conn, root_window, reload_atom)?;
+
     // 5. Initial rendering
     let state_cookie = conn.xkb_get_state(xkb::ID::USE_CORE_KBD.into())?;
     let state_reply = state_cookie.reply()?;
     let mut current_group: u8 = state_reply.group.into();
+    // What the layout memory stores; `current_group` may be held or latched
+    let mut locked_group: u8 = state_reply.locked_group.into();
+    let mut lock_indicators = LockIndicators::new(&conn)?;
+    let mut indicator_state = locks::get_indicator_state(&conn)?;
+    // A group that differs from the locked one is only active temporarily
+    let mut temporary = state_reply.group != state_reply.locked_group;
+    let mut variant = IconVariant {
+        style: config.icon.style(temporary),
+        locks: lock_indicators.locks(indicator_state, &config.indicators),
+    };
 
-    if let Some(name) = layout_names.get(current_group as usize) {
-        if let Some(pixels) = icon_cache.get(name) {
-            draw_icon(&conn, win_id, screen, pixels)?;
-        }
+    // Layout memory follows _NET_ACTIVE_WINDOW and _NET_CURRENT_DESKTOP
+    let active_window_atom = conn.intern_atom(false, b"_NET_ACTIVE_WINDOW")?.reply()?.atom;
+    let current_desktop_atom = conn.intern_atom(false, b"_NET_CURRENT_DESKTOP")?.reply()?.atom;
+    let active_window = memory::get_active_window(&conn, root_window, active_window_atom)?;
+    let current_desktop = memory::get_current_desktop(&conn, root_window, current_desktop_atom)?;
+    let mut layout_memory = LayoutMemory::new(config.memory.mode, active_window, current_desktop);
+    let rule_engine = RuleEngine::new(&conn)?;
+    layout_memory.record(locked_group);
+    if active_window != x11rb::NONE {
+        memory::watch_window(&conn, active_window)?;
+    }
+
+    if let Some(name) = layouts.names.get(current_group as usize) {
+        icon_cache.draw(&conn, &icon_window, name, variant)?;
     }
 
     println!("App started. Icon should now be IN the tray.");
 
     // 6. Main loop
+    let mut popup: Option<Popup> = None;
     loop {
         let event = conn.wait_for_event()?;
+
+        // While the layout menu is open it sees every event first
+        if let Some(menu) = &popup {
+            match menu.handle_event(&event) {
+                PopupAction::Ignored => {}
+                PopupAction::Consumed => continue,
+                PopupAction::Close => {
+                    if let Some(menu) = popup.take() {
+                        menu.close(&conn)?;
+                    }
+                    continue;
+                }
+                PopupAction::Select(group) => {
+                    if let Some(menu) = popup.take() {
+                        menu.close(&conn)?;
+                    }
+                    group::lock_group(&conn, group)?;
+                    continue;
+                }
+            }
+        }
+
         match event {
             x11rb::protocol::Event::XkbStateNotify(e) => {
+                locked_group = e.locked_group.into();
+                layout_memory.record(locked_group);
                 let new_group: u8 = e.group.into();
-                if new_group != current_group {
+                let new_temporary = e.group != e.locked_group;
+                if new_group != current_group || new_temporary != temporary {
                     current_group = new_group;
-                    if let Some(name) = layout_names.get(current_group as usize) {
-                        if let Some(pixels) = icon_cache.get(name) {
-                            draw_icon(&conn, win_id, screen, pixels)?;
-                        }
+                    temporary = new_temporary;
+                    variant.style = config.icon.style(temporary);
+                    if let Some(name) = layouts.names.get(current_group as usize) {
+                        icon_cache.draw(&conn, &icon_window, name, variant)?;
+                    }
+                }
+            }
+            x11rb::protocol::Event::XkbIndicatorStateNotify(e) => {
+                indicator_state = e.state;
+                let new_locks = lock_indicators.locks(indicator_state, &config.indicators);
+                if new_locks != variant.locks {
+                    variant.locks = new_locks;
+                    if let Some(name) = layouts.names.get(current_group as usize) {
+                        icon_cache.draw(&conn, &icon_window, name, variant)?;
+                    }
+                }
+            }
+            // Several of these arrive for a single setxkbmap; only a real
+            // change of the groups needs new icons
+            _ if is_keymap_change(&event, root_window, xkb_rules_atom) => {
+                let new_layouts = Layouts::load(&conn, root_window, &config.labels)?;
+                let names_changed = new_layouts != layouts;
+                if names_changed {
+                    println!("Detected layouts: {:?} {:?}", new_layouts.names, new_layouts.labels);
+                    layouts = new_layouts;
+                    layouts.warn_collisions();
+                    let state = conn.xkb_get_state(xkb::ID::USE_CORE_KBD.into())?.reply()?;
+                    current_group = state.group.into();
+                    locked_group = state.locked_group.into();
+                    fill_icon_cache(&conn, &mut icon_cache, &icon_window, &layouts, &font, &config, icon_size)?;
+                }
+                // The new keymap may also order its indicators differently
+                lock_indicators = LockIndicators::new(&conn)?;
+                let new_locks = lock_indicators.locks(indicator_state, &config.indicators);
+                if names_changed || new_locks != variant.locks {
+                    variant.locks = new_locks;
+                    if let Some(name) = layouts.names.get(current_group as usize) {
+                        icon_cache.draw(&conn, &icon_window, name, variant)?;
+                    }
+                }
+            }
+            x11rb::protocol::Event::PropertyNotify(e)
+                if e.window == root_window && e.atom == active_window_atom =>
+            {
+                let window = memory::get_active_window(&conn, root_window, active_window_atom)?;
+                if window != x11rb::NONE {
+                    memory::watch_window(&conn, window)?;
+                }
+                let target = match layout_memory.focus_changed(window) {
+                    Focus::Unchanged | Focus::Again(None) => None,
+                    Focus::Again(Some(group)) => Some(group),
+                    Focus::First => {
+                        // Remember the window right away, so its rule only applies once
+                        let forced = rule_engine.group_for(&conn, window, &config.rules, &layouts)?;
+                        layout_memory.record(forced.unwrap_or(locked_group));
+                        forced
+                    }
+                };
+                if let Some(group) = target {
+                    if group != locked_group {
+                        group::lock_group(&conn, group)?;
+                    }
+                }
+            }
+            x11rb::protocol::Event::PropertyNotify(e)
+                if e.window == root_window && e.atom == current_desktop_atom =>
+            {
+                let desktop = memory::get_current_desktop(&conn, root_window, current_desktop_atom)?;
+                if let Some(group) = layout_memory.desktop_changed(desktop) {
+                    if group != locked_group {
+                        group::lock_group(&conn, group)?;
                     }
                 }
             }
-            x11rb::protocol::Event::Expose(e) => {
-                if e.count == 0 {
-                    if let Some(name) = layout_names.get(current_group as usize) {
-                        if let Some(pixels) = icon_cache.get(name) {
-                            draw_icon(&conn, win_id, screen, pixels)?;
+            // The tray went away; hide (or float) until a new one shows up
+            x11rb::protocol::Event::DestroyNotify(e) if e.window == manager => {
+                println!("System Tray is gone, waiting for it to come back...");
+                manager = x11rb::NONE;
+                xembed.reset();
+                conn.unmap_window(win_id)?;
+                if config.tray.fallback == TrayFallback::Floating {
+                    floating = Some(Floating::show(&conn, screen, win_id, config.icon.size, config.floating.click_through)?);
+                }
+                conn.flush()?;
+            }
+            x11rb::protocol::Event::ClientMessage(e) if e.type_ == manager_atom => {
+                if let Some(new_manager) = tray::new_manager(&e, tray_atom) {
+                    println!("System Tray (re)started, docking again");
+                    manager = new_manager;
+                    if let Some(floating) = floating.take() {
+                        floating.leave(&conn, win_id)?;
+                    }
+
+                    // The new tray may not offer the visual the window was made for
+                    let argb_visual = tray::argb_visual(&conn, screen, manager)?;
+                    let background = tray::effective_background(
+                        config.icon.background_mode,
+                        argb_visual,
+                        config.icon.background.a < 255,
+                    );
+                    if !icon_window.suits(background, argb_visual) {
+                        icon_cache.destroy(&conn)?;
+                        icon_window.destroy(&conn)?;
+                        icon_window =
+                            tray::create_icon_window(&conn, screen, background, argb_visual, config.icon.size, icon_events)?;
+                        win_id = icon_window.id;
+                        println!("Icon background mode: {:?}", icon_window.background);
+                        xembed.setup_window(&conn, win_id)?;
+
+                        icon_size = IconSize::square(config.icon.size);
+                        icon_cache = IconCache::new(&conn, &icon_window)?;
+                        fill_icon_cache(&conn, &mut icon_cache, &icon_window, &layouts, &font, &config, icon_size)?;
+                        if let Some(name) = layouts.names.get(current_group as usize) {
+                            icon_cache.draw(&conn, &icon_window, name, variant)?;
                         }
                     }
+                    tray::watch_manager(&conn, manager)?;
+                    tray::dock(&conn, manager, win_id)?;
+                    conn.flush()?;
+                }
+            }
+            x11rb::protocol::Event::ClientMessage(e) if e.type_ == xembed.atom && e.window == win_id => {
+                xembed.handle_message(&conn, &e)?;
+            }
+            x11rb::protocol::Event::DestroyNotify(e) => {
+                layout_memory.forget(e.window);
+            }
+            // The tray decides how big the icon is; re-render to fill it
+            x11rb::protocol::Event::ConfigureNotify(e) if e.window == win_id => {
+                let new_size = IconSize { width: e.width, height: e.height };
+                let resized = new_size != icon_size && e.width > 0 && e.height > 0;
+                if resized {
+                    icon_size = new_size;
+                    fill_icon_cache(&conn, &mut icon_cache, &icon_window, &layouts, &font, &config, icon_size)?;
+                }
+                // A pseudo-transparent icon also has to follow the panel under it when moved
+                if resized || icon_window.background == BackgroundMode::ParentRelative {
+                    if let Some(name) = layouts.names.get(current_group as usize) {
+                        icon_cache.draw(&conn, &icon_window, name, variant)?;
+                    }
+                }
+            }
+            // Pixmap backgrounds are repainted by the server itself
+            x11rb::protocol::Event::Expose(e)
+                if e.count == 0 && icon_window.background == BackgroundMode::ParentRelative =>
+            {
+                if let Some(name) = layouts.names.get(current_group as usize) {
+                    icon_cache.draw(&conn, &icon_window, name, variant)?;
+                }
+            }
+            // Left click: switch to the next layout
+            // (the floating widget switches on release, so that it can be dragged)
+            x11rb::protocol::Event::ButtonPress(e) if e.detail == 1 => match &mut floating {
+                Some(floating) => floating.press(&e),
+                None => group::lock_group(&conn, group::next_group(current_group, layouts.names.len()))?,
+            },
+            x11rb::protocol::Event::ButtonRelease(e)
+                if e.detail == 1 && floating.as_mut().is_some_and(|floating| floating.release()) =>
+            {
+                group::lock_group(&conn, group::next_group(current_group, layouts.names.len()))?;
+            }
+            x11rb::protocol::Event::MotionNotify(e) if e.event == win_id => {
+                if let Some(floating) = &mut floating {
+                    floating.motion(&conn, win_id, &e)?;
+                }
+            }
+            x11rb::protocol::Event::VisibilityNotify(e)
+                if e.window == win_id && e.state != xproto::Visibility::UNOBSCURED =>
+            {
+                if let Some(floating) = &floating {
+                    floating.raise(&conn, win_id)?;
+                }
+            }
+            // Mouse wheel: scroll up goes back, scroll down goes forward
+            x11rb::protocol::Event::ButtonPress(e) if e.detail == 4 => {
+                group::lock_group(&conn, group::prev_group(current_group, layouts.names.len()))?;
+            }
+            x11rb::protocol::Event::ButtonPress(e) if e.detail == 5 => {
+                group::lock_group(&conn, group::next_group(current_group, layouts.names.len()))?;
+            }
+            // Right click: menu with all layouts
+            x11rb::protocol::Event::ButtonPress(e) if e.detail == 3 => {
+                popup = Some(Popup::open(
+                    &conn,
+                    screen,
+                    win_id,
+                    &layouts.names,
+                    current_group as usize,
+                    &font,
+                    &config,
+                )?);
+            }
+            x11rb::protocol::Event::ClientMessage(e) if e.type_ == reload_atom => {
+                // Keep running with the old settings if the new file is broken
+                let mut new_config = match Config::load() {
+                    Ok(c) => c,
+                    Err(err) => {
+                        eprintln!("Config reload failed, keeping previous settings: {}", err);
+                        continue;
+                    }
+                };
+                let new_font = font::load_font(&new_config.font);
+
+                if new_config.icon.size != config.icon.size {
+                    let size = new_config.icon.size as u32;
+                    conn.configure_window(win_id, &xproto::ConfigureWindowAux::new().width(size).height(size))?;
+                }
+                for setting in config.keep_startup_settings(&mut new_config) {
+                    println!("{} changes take effect after a restart", setting);
+                }
+                layout_memory.set_mode(new_config.memory.mode);
+                config = new_config;
+                font = new_font;
+                layouts.relabel(&config.labels);
+                layouts.warn_collisions();
+                variant = IconVariant {
+                    style: config.icon.style(temporary),
+                    locks: lock_indicators.locks(indicator_state, &config.indicators),
+                };
+                fill_icon_cache(&conn, &mut icon_cache, &icon_window, &layouts, &font, &config, icon_size)?;
+                println!("Config reloaded");
+
+                if let Some(name) = layouts.names.get(current_group as usize) {
+                    icon_cache.draw(&conn, &icon_window, name, variant)?;
                 }
             }
             _ => {}
@@ -141,146 +431,91 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 // --- Helpers ---
 
-fn dock_window_to_tray(conn: &impl Connection, screen_num: usize, win_id: xproto::Window) -> Result<(), Box<dyn Error>> {
-    let tray_atom_name = format!("_NET_SYSTEM_TRAY_S{}", screen_num);
-    let tray_atom = conn.intern_atom(false, tray_atom_name.as_bytes())?.reply()?.atom;
-
-    // Check if there is an owner of the tray atom
-    let manager_reply = conn.get_selection_owner(tray_atom)?.reply()?;
-    let manager_win = manager_reply.owner;
-
-    if manager_win == x11rb::NONE {
-        return Err("No system tray detected".into());
-    }
+/// Wakes up the main loop from the watcher thread: it sends a ClientMessage
+/// to a window of our own over a second connection. That window is not the
+/// icon, which gets replaced when a tray with another visual shows up.
+fn spawn_config_watcher(
+    conn: &impl Connection,
+    root: xproto::Window,
+    reload_atom: xproto::Atom,
+) -> Result<(), Box<dyn Error>> {
+    let Some(path) = Config::path() else {
+        return Ok(());
+    };
+    let win_id = conn.generate_id()?;
+    conn.create_window(
+        0,
+        win_id,
+        root,
+        0, 0, 1, 1,
+        0,
+        xproto::WindowClass::INPUT_ONLY,
+        x11rb::COPY_FROM_PARENT,
+        &xproto::CreateWindowAux::new(),
+    )?;
+    conn.flush()?;
+    let (notify_conn, _) = x11rb::connect(None)?;
 
-    let opcode_atom = conn.intern_atom(false, b"_NET_SYSTEM_TRAY_OPCODE")?.reply()?.atom;
+    config::watch(path, move || {
+        if let Err(e) = send_client_message(&notify_conn, win_id, reload_atom) {
+            eprintln!("Could not request config reload: {}", e);
+        }
+    });
+    Ok(())
+}
 
+fn send_client_message(conn: &impl Connection, win_id: xproto::Window, type_: xproto::Atom) -> Result<(), Box<dyn Error>> {
     let event = ClientMessageEvent {
         response_type: xproto::CLIENT_MESSAGE_EVENT,
         format: 32,
-        window: manager_win,
-        type_: opcode_atom,
-        data: xproto::ClientMessageData::from([0, 0, win_id, 0, 0]),
+        window: win_id,
+        type_,
+        data: xproto::ClientMessageData::from([0u32; 5]),
         sequence: 0,
     };
 
-    conn.send_event(false, manager_win, EventMask::NO_EVENT, event)?;
-    Ok(())
-}
-
-fn draw_icon(
-    conn: &impl Connection,
-    win: xproto::Window,
-    screen: &xproto::Screen,
-    pixels: &[u8]
-) -> Result<(), Box<dyn Error>> {
-    let gc = conn.generate_id()?;
-    conn.create_gc(gc, win, &xproto::CreateGCAux::new())?;
-
-    conn.put_image(
-        xproto::ImageFormat::Z_PIXMAP,
-        win,
-        gc,
-        ICON_SIZE, ICON_SIZE,
-        0, 0,
-        0,
-        screen.root_depth,
-        pixels,
-    )?;
-
-    conn.free_gc(gc)?;
+    // An empty event mask delivers the event to the window's creator, i.e. us
+    conn.send_event(false, win_id, EventMask::NO_EVENT, event)?;
     conn.flush()?;
     Ok(())
 }
 
-fn shorten_name(name: &str) -> String {
-    let lower = name.to_lowercase();
-    if lower.contains("ru") || lower.contains("russian") { return "RU".to_string(); }
-    if lower.contains("us") || lower.contains("english") { return "EN".to_string(); }
-    if lower.contains("ua") { return "UA".to_string(); }
-    name.chars().take(2).collect::<String>().to_uppercase()
-}
-
-fn get_layout_names(conn: &impl Connection) -> Result<Vec<String>, Box<dyn Error>> {
-    let names = conn.xkb_get_names(xkb::ID::USE_CORE_KBD.into(), xkb::NameDetail::GROUP_NAMES)?.reply()?;
-    let mut res = Vec::new();
-    if let Some(groups) = names.value_list.groups {
-        for atom in groups {
-            if atom == 0 { break; }
-            let name = String::from_utf8(conn.get_atom_name(atom)?.reply()?.name)?;
-            res.push(name);
-        }
+/// Events after which the groups or their layouts may differ
+fn is_keymap_change(event: &x11rb::protocol::Event, root: xproto::Window, xkb_rules_atom: xproto::Atom) -> bool {
+    match event {
+        x11rb::protocol::Event::XkbNewKeyboardNotify(_)
+        | x11rb::protocol::Event::XkbMapNotify(_)
+        | x11rb::protocol::Event::XkbNamesNotify(_) => true,
+        x11rb::protocol::Event::PropertyNotify(e) => e.window == root && e.atom == xkb_rules_atom,
+        _ => false,
     }
-    if res.is_empty() { res.push("US".to_string()); }
-    Ok(res)
 }
 
-fn render_text_icon(text: &str, font: &FontRef) -> RgbaImage {
-    // Colors
-    let bg_color = [35u8, 35u8, 35u8];
-    let fg_color = [255u8, 255u8, 255u8];
-
-    let mut image = RgbaImage::from_pixel(
-        ICON_SIZE as u32,
-        ICON_SIZE as u32,
-        Rgba([bg_color[0], bg_color[1], bg_color[2], 255])
-    );
-
-    let scale = PxScale { x: 16.0, y: 16.0 };
-    let scaled_font = font.as_scaled(scale);
-
-    let mut text_width = 0.0;
-    for c in text.chars() {
-        text_width += scaled_font.h_advance(scaled_font.glyph_id(c));
+/// (Re)renders the icon of every layout into the cache
+fn fill_icon_cache(
+    conn: &impl Connection,
+    icon_cache: &mut IconCache,
+    window: &tray::IconWindow,
+    layouts: &Layouts,
+    font: &FontArc,
+    config: &Config,
+    icon_size: IconSize,
+) -> Result<(), Box<dyn Error>> {
+    icon_cache.clear(conn)?;
+    let mut styles = vec![config.icon.locked_style];
+    if config.icon.temporary_style != config.icon.locked_style {
+        styles.push(config.icon.temporary_style);
     }
-
-    let start_x = ((ICON_SIZE as f32 - text_width) / 2.0).round() - 2.0;
-
-    let v_metrics = scaled_font.ascent() - scaled_font.descent();
-    let start_y = ((ICON_SIZE as f32 - v_metrics) / 2.0 + scaled_font.ascent()).round() - 1.0;
-
-    let mut current_x = start_x;
-
-    for c in text.chars() {
-        let glyph_id = scaled_font.glyph_id(c);
-        let glyph = glyph_id.with_scale_and_position(scale, ab_glyph::point(current_x, start_y));
-
-        if let Some(outlined) = font.outline_glyph(glyph) {
-            let bounds = outlined.px_bounds();
-
-            outlined.draw(|x, y, coverage| {
-                let px = x as u32 + bounds.min.x as u32;
-                let py = y as u32 + bounds.min.y as u32;
-
-                if px < ICON_SIZE as u32 && py < ICON_SIZE as u32 {
-                    let pixel = image.get_pixel_mut(px, py);
-
-                    let blend = |bg: u8, fg: u8, v: f32| -> u8 {
-                        ((bg as f32 * (1.0 - v)) + (fg as f32 * v)) as u8
-                    };
-
-                    let r = blend(bg_color[0], fg_color[0], coverage);
-                    let g = blend(bg_color[1], fg_color[1], coverage);
-                    let b = blend(bg_color[2], fg_color[2], coverage);
-
-                    *pixel = Rgba([r, g, b, 255]);
-                }
-            });
+    for (i, name) in layouts.names.iter().enumerate() {
+        let (label, badge) = (&layouts.labels[i], &layouts.badges[i]);
+        for style in styles.iter().copied() {
+            let image = render::render_text_icon(label, badge, style, font, config, icon_size);
+            for locks in Locks::variants(&config.indicators) {
+                let mut marked = image.clone();
+                render::draw_locks(&mut marked, locks, config);
+                icon_cache.insert(conn, window, name.clone(), IconVariant { style, locks }, marked)?;
+            }
         }
-        current_x += scaled_font.h_advance(glyph_id);
     }
-    image
+    Ok(())
 }
-
-fn render_icon_bgra(text: &str, font: &FontRef) -> Vec<u8> {
-    let img = render_text_icon(text, font);
-    let mut data = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
-    for pixel in img.pixels() {
-        let [r, g, b, _a] = pixel.0;
-        data.push(b);
-        data.push(g);
-        data.push(r);
-        data.push(0);
-    }
-    data
-}
\ No newline at end of file
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{self, AtomEnum, ChangeWindowAttributesAux, ConnectionExt as _, EventMask};
+
+use crate::config::MemoryMode;
+
+/// Remembers the locked XKB group per top-level window
+/// (as reported by _NET_ACTIVE_WINDOW) or per virtual desktop
+/// (_NET_CURRENT_DESKTOP) and hands it back on refocus / desktop switch.
+/// Windows are tracked in every mode, so that per-application rules
+/// can tell a window's first focus from later ones.
+pub struct LayoutMemory {
+    mode: MemoryMode,
+    active_window: xproto::Window,
+    groups: HashMap<xproto::Window, u8>,
+    current_desktop: Option<u32>,
+    desktop_groups: HashMap<u32, u8>,
+}
+
+/// Result of a focus change
+pub enum Focus {
+    /// Same window as before, or no window at all
+    Unchanged,
+    /// The window has never been focused before
+    First,
+    /// Seen before; carries the group to restore, if the mode restores one
+    Again(Option<u8>),
+}
+
+impl LayoutMemory {
+    pub fn new(mode: MemoryMode, active_window: xproto::Window, current_desktop: Option<u32>) -> Self {
+        LayoutMemory {
+            mode,
+            active_window,
+            groups: HashMap::new(),
+            current_desktop,
+            desktop_groups: HashMap::new(),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: MemoryMode) {
+        self.mode = mode;
+    }
+
+    /// Called on every XKB state change with the newly locked group
+    pub fn record(&mut self, group: u8) {
+        if self.active_window != x11rb::NONE {
+            self.groups.insert(self.active_window, group);
+        }
+        if let Some(desktop) = self.current_desktop {
+            self.desktop_groups.insert(desktop, group);
+        }
+    }
+
+    pub fn focus_changed(&mut self, window: xproto::Window) -> Focus {
+        if window == self.active_window || window == x11rb::NONE {
+            self.active_window = window;
+            return Focus::Unchanged;
+        }
+        self.active_window = window;
+        match self.groups.get(&window) {
+            None => Focus::First,
+            Some(&group) => match self.mode {
+                MemoryMode::Window => Focus::Again(Some(group)),
+                MemoryMode::Global | MemoryMode::Desktop => Focus::Again(None),
+            },
+        }
+    }
+
+    /// Called on desktop switch; returns the group last used on that desktop
+    /// when remembering per desktop.
+    pub fn desktop_changed(&mut self, desktop: Option<u32>) -> Option<u8> {
+        if desktop == self.current_desktop {
+            return None;
+        }
+        self.current_desktop = desktop;
+        match self.mode {
+            MemoryMode::Desktop => self.desktop_groups.get(&desktop?).copied(),
+            MemoryMode::Global | MemoryMode::Window => None,
+        }
+    }
+
+    pub fn forget(&mut self, window: xproto::Window) {
+        self.groups.remove(&window);
+    }
+}
+
+/// Asks for DestroyNotify on a client window so its entry can be forgotten
+pub fn watch_window(conn: &impl Connection, window: xproto::Window) -> Result<(), Box<dyn Error>> {
+    conn.change_window_attributes(window, &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY))?;
+    Ok(())
+}
+
+pub fn get_active_window(
+    conn: &impl Connection,
+    root: xproto::Window,
+    active_atom: xproto::Atom,
+) -> Result<xproto::Window, Box<dyn Error>> {
+    let reply = conn.get_property(false, root, active_atom, AtomEnum::WINDOW, 0, 1)?.reply()?;
+    Ok(reply.value32().and_then(|mut v| v.next()).unwrap_or(x11rb::NONE))
+}
+
+/// `None` when the window manager does not support virtual desktops
+pub fn get_current_desktop(
+    conn: &impl Connection,
+    root: xproto::Window,
+    desktop_atom: xproto::Atom,
+) -> Result<Option<u32>, Box<dyn Error>> {
+    let reply = conn.get_property(false, root, desktop_atom, AtomEnum::CARDINAL, 0, 1)?.reply()?;
+    Ok(reply.value32().and_then(|mut v| v.next()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: xproto::Window = 0x100;
+    const B: xproto::Window = 0x200;
+
+    #[test]
+    fn window_mode_restores_per_window() {
+        let mut memory = LayoutMemory::new(MemoryMode::Window, A, None);
+        memory.record(1);
+
+        assert!(matches!(memory.focus_changed(B), Focus::First));
+        memory.record(0);
+        assert!(matches!(memory.focus_changed(A), Focus::Again(Some(1))));
+        assert!(matches!(memory.focus_changed(B), Focus::Again(Some(0))));
+        assert!(matches!(memory.focus_changed(B), Focus::Unchanged));
+    }
+
+    #[test]
+    fn no_window_keeps_the_previous_one() {
+        let mut memory = LayoutMemory::new(MemoryMode::Window, A, None);
+        memory.record(1);
+        assert!(matches!(memory.focus_changed(x11rb::NONE), Focus::Unchanged));
+        // Nothing is focused, so nothing gets recorded
+        memory.record(0);
+        assert!(matches!(memory.focus_changed(A), Focus::Again(Some(1))));
+    }
+
+    #[test]
+    fn forgotten_windows_start_over() {
+        let mut memory = LayoutMemory::new(MemoryMode::Window, A, None);
+        memory.record(1);
+        assert!(matches!(memory.focus_changed(B), Focus::First));
+        memory.forget(A);
+        assert!(matches!(memory.focus_changed(A), Focus::First));
+    }
+
+    #[test]
+    fn global_mode_tracks_windows_without_restoring() {
+        let mut memory = LayoutMemory::new(MemoryMode::Global, A, None);
+        memory.record(1);
+        assert!(matches!(memory.focus_changed(B), Focus::First));
+        assert!(matches!(memory.focus_changed(A), Focus::Again(None)));
+    }
+
+    #[test]
+    fn mode_switch_applies_to_known_windows() {
+        let mut memory = LayoutMemory::new(MemoryMode::Global, A, None);
+        memory.record(1);
+        memory.focus_changed(B);
+        memory.set_mode(MemoryMode::Window);
+        assert!(matches!(memory.focus_changed(A), Focus::Again(Some(1))));
+    }
+
+    #[test]
+    fn desktop_mode_restores_per_desktop() {
+        let mut memory = LayoutMemory::new(MemoryMode::Desktop, A, Some(0));
+        memory.record(1);
+        assert_eq!(memory.desktop_changed(Some(1)), None);
+        memory.record(2);
+
+        assert_eq!(memory.desktop_changed(Some(0)), Some(1));
+        assert_eq!(memory.desktop_changed(Some(0)), None);
+        assert_eq!(memory.desktop_changed(Some(1)), Some(2));
+        // Windows are still tracked, but not restored
+        assert!(matches!(memory.focus_changed(B), Focus::First));
+        assert!(matches!(memory.focus_changed(A), Focus::Again(None)));
+    }
+
+    #[test]
+    fn desktops_only_restore_in_desktop_mode() {
+        let mut memory = LayoutMemory::new(MemoryMode::Window, A, Some(0));
+        memory.record(1);
+        memory.desktop_changed(Some(1));
+        memory.record(2);
+        assert_eq!(memory.desktop_changed(Some(0)), None);
+    }
+
+    #[test]
+    fn no_desktop_support() {
+        let mut memory = LayoutMemory::new(MemoryMode::Desktop, A, None);
+        memory.record(1);
+        assert_eq!(memory.desktop_changed(None), None);
+        assert_eq!(memory.desktop_changed(Some(0)), None);
+    }
+}
@@ -0,0 +1,278 @@
+use std::error::Error;
+
+use ab_glyph::{Font, FontArc, PxScale, ScaleFont};
+use image::{Rgba, RgbaImage};
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    self, ConnectionExt as _, CreateWindowAux, EventMask, GrabMode, GrabStatus, InputFocus,
+    NotifyMode, WindowClass,
+};
+use x11rb::protocol::Event;
+
+use crate::config::Config;
+use crate::render;
+use crate::visual::PixelFormat;
+
+const PADDING: u16 = 6;
+const XK_ESCAPE: xproto::Keysym = 0xff1b;
+
+/// What the main loop should do with an event after the popup has seen it
+pub enum PopupAction {
+    /// Not related to the popup, handle as usual
+    Ignored,
+    /// Handled by the popup, nothing else to do
+    Consumed,
+    Close,
+    /// The user picked this group
+    Select(u8),
+}
+
+/// Right-click menu listing every layout, with the active one highlighted.
+/// The menu is uploaded once into a pixmap that serves as the window
+/// background, so the server repaints it on its own.
+pub struct Popup {
+    win: xproto::Window,
+    width: u16,
+    height: u16,
+    row_height: u16,
+    rows: usize,
+    escape: Option<xproto::Keycode>,
+    /// Focus (and its revert_to) before the menu took it, given back on close
+    previous_focus: (xproto::Window, InputFocus),
+}
+
+impl Popup {
+    pub fn open(
+        conn: &impl Connection,
+        screen: &xproto::Screen,
+        anchor: xproto::Window,
+        items: &[String],
+        active: usize,
+        font: &FontArc,
+        config: &Config,
+    ) -> Result<Popup, Box<dyn Error>> {
+        let scale = PxScale { x: config.font.size, y: config.font.size };
+        let scaled_font = font.as_scaled(scale);
+        let row_height = scaled_font.height().ceil() as u16 + PADDING;
+        let text_width = items
+            .iter()
+            .map(|item| render::text_width(font, scale, item))
+            .fold(0.0, f32::max);
+        let width = (text_width.ceil() as u16 + 2 * PADDING).max(config.icon.size);
+        let height = row_height * items.len().max(1) as u16;
+
+        let image = render_menu(items, active, width, row_height, font, scale, config);
+        let (x, y) = place(conn, screen, anchor, width, height)?;
+
+        let format = PixelFormat::for_visual(conn.setup(), screen.root_depth, screen.root_visual)?;
+        let pixmap = upload(conn, screen.root, &format, &image)?;
+        let win = conn.generate_id()?;
+        let win_aux = CreateWindowAux::new()
+            .background_pixmap(pixmap)
+            .override_redirect(1)
+            .event_mask(
+                EventMask::BUTTON_PRESS
+                    | EventMask::BUTTON_RELEASE
+                    | EventMask::KEY_PRESS
+                    | EventMask::FOCUS_CHANGE,
+            );
+        conn.create_window(
+            x11rb::COPY_FROM_PARENT as u8,
+            win,
+            screen.root,
+            x, y, width, height,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            x11rb::COPY_FROM_PARENT,
+            &win_aux,
+        )?;
+        // The window keeps its own reference to the background
+        conn.free_pixmap(pixmap)?;
+        conn.map_window(win)?;
+
+        let focus = conn.get_input_focus()?.reply()?;
+
+        // Grab input so that a click anywhere else or Escape closes the menu
+        let pointer_events = EventMask::BUTTON_PRESS | EventMask::BUTTON_RELEASE;
+        let pointer = conn
+            .grab_pointer(true, win, pointer_events, GrabMode::ASYNC, GrabMode::ASYNC, x11rb::NONE, x11rb::NONE, x11rb::CURRENT_TIME)?
+            .reply()?;
+        let keyboard = conn
+            .grab_keyboard(true, win, x11rb::CURRENT_TIME, GrabMode::ASYNC, GrabMode::ASYNC)?
+            .reply()?;
+        if pointer.status != GrabStatus::SUCCESS || keyboard.status != GrabStatus::SUCCESS {
+            println!("Could not grab input for the layout menu, it will close on focus loss only");
+        }
+        conn.set_input_focus(InputFocus::PARENT, win, x11rb::CURRENT_TIME)?;
+
+        conn.flush()?;
+        Ok(Popup {
+            win,
+            width,
+            height,
+            row_height,
+            rows: items.len(),
+            escape: find_keycode(conn, XK_ESCAPE)?,
+            previous_focus: (focus.focus, focus.revert_to),
+        })
+    }
+
+    pub fn close(self, conn: &impl Connection) -> Result<(), Box<dyn Error>> {
+        conn.ungrab_pointer(x11rb::CURRENT_TIME)?;
+        conn.ungrab_keyboard(x11rb::CURRENT_TIME)?;
+        // Otherwise focus falls back to the root and typing goes nowhere.
+        // Unless it has moved on to another window already.
+        if conn.get_input_focus()?.reply()?.focus == self.win {
+            let (focus, revert_to) = self.previous_focus;
+            conn.set_input_focus(revert_to, focus, x11rb::CURRENT_TIME)?;
+        }
+        conn.destroy_window(self.win)?;
+        conn.flush()?;
+        Ok(())
+    }
+
+    pub fn handle_event(&self, event: &Event) -> PopupAction {
+        match event {
+            Event::KeyPress(e) if Some(e.detail) == self.escape => PopupAction::Close,
+            Event::KeyPress(_) => PopupAction::Consumed,
+            Event::FocusOut(e)
+                if e.event == self.win && e.mode != NotifyMode::GRAB && e.mode != NotifyMode::UNGRAB =>
+            {
+                PopupAction::Close
+            }
+            // Any press outside the menu (including on the tray icon) dismisses it
+            Event::ButtonPress(e) => {
+                if e.event == self.win && self.row_at(e.event_x, e.event_y).is_some() {
+                    PopupAction::Consumed
+                } else {
+                    PopupAction::Close
+                }
+            }
+            Event::ButtonRelease(e) if e.event == self.win => match self.row_at(e.event_x, e.event_y) {
+                Some(row) if e.detail == 1 || e.detail == 3 => PopupAction::Select(row as u8),
+                _ => PopupAction::Consumed,
+            },
+            _ => PopupAction::Ignored,
+        }
+    }
+
+    fn row_at(&self, x: i16, y: i16) -> Option<usize> {
+        if x < 0 || y < 0 || x as u16 >= self.width || y as u16 >= self.height {
+            return None;
+        }
+        let row = (y as u16 / self.row_height) as usize;
+        (row < self.rows).then_some(row)
+    }
+}
+
+fn render_menu(
+    items: &[String],
+    active: usize,
+    width: u16,
+    row_height: u16,
+    font: &FontArc,
+    scale: PxScale,
+    config: &Config,
+) -> RgbaImage {
+    let bg_color = config.icon.background.rgb();
+    let fg_color = config.icon.foreground.rgb();
+    let height = row_height as u32 * items.len().max(1) as u32;
+
+    let mut image = RgbaImage::from_pixel(
+        width as u32,
+        height,
+        Rgba([bg_color[0], bg_color[1], bg_color[2], 255]),
+    );
+
+    let ascent = font.as_scaled(scale).ascent();
+    for (i, item) in items.iter().enumerate() {
+        let top = i as u32 * row_height as u32;
+
+        // The active layout is drawn inverted
+        let text_color = if i == active {
+            for y in top..top + row_height as u32 {
+                for x in 0..width as u32 {
+                    image.put_pixel(x, y, Rgba([fg_color[0], fg_color[1], fg_color[2], 255]));
+                }
+            }
+            bg_color
+        } else {
+            fg_color
+        };
+
+        let baseline = (top as f32 + (PADDING / 2) as f32 + ascent).round();
+        render::draw_text(&mut image, item, font, scale, PADDING as f32, baseline, text_color);
+    }
+    image
+}
+
+/// Puts the rendered menu into a new pixmap of the root window's depth
+fn upload(
+    conn: &impl Connection,
+    root: xproto::Window,
+    format: &PixelFormat,
+    image: &RgbaImage,
+) -> Result<xproto::Pixmap, Box<dyn Error>> {
+    let (width, height) = (image.width() as u16, image.height() as u16);
+    let pixmap = conn.generate_id()?;
+    conn.create_pixmap(format.depth, pixmap, root, width, height)?;
+
+    let gc = conn.generate_id()?;
+    conn.create_gc(gc, pixmap, &xproto::CreateGCAux::new())?;
+    conn.put_image(
+        xproto::ImageFormat::Z_PIXMAP,
+        pixmap,
+        gc,
+        width, height,
+        0, 0,
+        0,
+        format.depth,
+        &format.encode(image),
+    )?;
+    conn.free_gc(gc)?;
+    Ok(pixmap)
+}
+
+/// Opens the menu away from the screen edge the tray sits on, so it works for
+/// panels at the top, bottom, left or right, then keeps it fully on screen.
+fn place(
+    conn: &impl Connection,
+    screen: &xproto::Screen,
+    anchor: xproto::Window,
+    width: u16,
+    height: u16,
+) -> Result<(i16, i16), Box<dyn Error>> {
+    let geometry = conn.get_geometry(anchor)?.reply()?;
+    let pos = conn.translate_coordinates(anchor, screen.root, 0, 0)?.reply()?;
+
+    let (ax, ay) = (pos.dst_x as i32, pos.dst_y as i32);
+    let (aw, ah) = (geometry.width as i32, geometry.height as i32);
+    let (w, h) = (width as i32, height as i32);
+    let (sw, sh) = (screen.width_in_pixels as i32, screen.height_in_pixels as i32);
+
+    let distances = [ay, sh - (ay + ah), ax, sw - (ax + aw)];
+    let nearest = (0..4).min_by_key(|&i| distances[i]).unwrap_or(1);
+    let (x, y) = match nearest {
+        0 => (ax, ay + ah), // top panel: open downwards
+        1 => (ax, ay - h),  // bottom panel: open upwards
+        2 => (ax + aw, ay), // left panel: open to the right
+        _ => (ax - w, ay),  // right panel: open to the left
+    };
+
+    let x = x.clamp(0, (sw - w).max(0));
+    let y = y.clamp(0, (sh - h).max(0));
+    Ok((x as i16, y as i16))
+}
+
+fn find_keycode(conn: &impl Connection, keysym: xproto::Keysym) -> Result<Option<xproto::Keycode>, Box<dyn Error>> {
+    let setup = conn.setup();
+    let (min, max) = (setup.min_keycode, setup.max_keycode);
+    let mapping = conn.get_keyboard_mapping(min, max - min + 1)?.reply()?;
+    let per_keycode = mapping.keysyms_per_keycode.max(1) as usize;
+
+    Ok(mapping
+        .keysyms
+        .chunks(per_keycode)
+        .position(|syms| syms.contains(&keysym))
+        .map(|i| min + i as u8))
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use ab_glyph::{Font, FontArc, PxScale, ScaleFont};
+use image::{Rgba, RgbaImage};
+use x11rb::connection::Connection;
+use x11rb::errors::ReplyError;
+use x11rb::protocol::xproto::{self, ConnectionExt as _};
+
+use crate::config::{BackgroundMode, Config, GroupStyle};
+use crate::locks::Locks;
+use crate::tray::IconWindow;
+
+/// Size of the icon window: the configured size until the tray resizes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IconSize {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl IconSize {
+    pub fn square(size: u16) -> Self {
+        IconSize { width: size, height: size }
+    }
+}
+
+/// Which version of a layout's icon to show
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IconVariant {
+    pub style: GroupStyle,
+    pub locks: Locks,
+}
+
+/// Rendered icons, one per layout and variant. Opaque and ARGB icons are uploaded once
+/// into server-side pixmaps and shown as the window background, so a group
+/// change or Expose costs a couple of tiny requests instead of a put_image.
+pub struct IconCache {
+    gc: xproto::Gcontext,
+    icons: HashMap<(String, IconVariant), CachedIcon>,
+}
+
+enum CachedIcon {
+    Pixmap(xproto::Pixmap),
+    /// Straight RGBA label, blended with the panel on every draw
+    /// (`BackgroundMode::ParentRelative`)
+    Label(RgbaImage),
+}
+
+impl IconCache {
+    /// The GC is created once and lives as long as the window
+    pub fn new(conn: &impl Connection, window: &IconWindow) -> Result<Self, Box<dyn Error>> {
+        let gc = conn.generate_id()?;
+        conn.create_gc(gc, window.id, &xproto::CreateGCAux::new())?;
+        Ok(IconCache { gc, icons: HashMap::new() })
+    }
+
+    pub fn insert(
+        &mut self,
+        conn: &impl Connection,
+        window: &IconWindow,
+        name: String,
+        variant: IconVariant,
+        image: RgbaImage,
+    ) -> Result<(), Box<dyn Error>> {
+        let icon = match window.background {
+            BackgroundMode::ParentRelative => CachedIcon::Label(image),
+            BackgroundMode::Argb | BackgroundMode::Solid => {
+                let (width, height) = (image.width() as u16, image.height() as u16);
+                let pixmap = conn.generate_id()?;
+                conn.create_pixmap(window.format.depth, pixmap, window.id, width, height)?;
+                conn.put_image(
+                    xproto::ImageFormat::Z_PIXMAP,
+                    pixmap,
+                    self.gc,
+                    width, height,
+                    0, 0,
+                    0,
+                    window.format.depth,
+                    &window.format.encode(&image),
+                )?;
+                CachedIcon::Pixmap(pixmap)
+            }
+        };
+        if let Some(old) = self.icons.insert((name, variant), icon) {
+            free_icon(conn, old)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the icons and the GC, before the window goes away
+    pub fn destroy(mut self, conn: &impl Connection) -> Result<(), Box<dyn Error>> {
+        self.clear(conn)?;
+        conn.free_gc(self.gc)?;
+        Ok(())
+    }
+
+    /// Drops every icon, e.g. before re-rendering at a new size
+    pub fn clear(&mut self, conn: &impl Connection) -> Result<(), Box<dyn Error>> {
+        for (_, icon) in self.icons.drain() {
+            free_icon(conn, icon)?;
+        }
+        Ok(())
+    }
+
+    pub fn draw(
+        &self,
+        conn: &impl Connection,
+        window: &IconWindow,
+        name: &str,
+        variant: IconVariant,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.icons.get(&(name.to_string(), variant)) {
+            None => return Ok(()),
+            Some(CachedIcon::Pixmap(pixmap)) => {
+                // The server keeps its own reference, so the pixmap may be
+                // freed later while still being the background
+                let aux = xproto::ChangeWindowAttributesAux::new().background_pixmap(*pixmap);
+                conn.change_window_attributes(window.id, &aux)?;
+                conn.clear_area(false, window.id, 0, 0, 0, 0)?;
+            }
+            Some(CachedIcon::Label(label)) => {
+                let (width, height) = (label.width() as u16, label.height() as u16);
+
+                // Let the server paint the panel background into the window,
+                // read it back and put the label on top of it
+                conn.clear_area(false, window.id, 0, 0, 0, 0)?;
+                let cookie = conn.get_image(xproto::ImageFormat::Z_PIXMAP, window.id, 0, 0, width, height, !0)?;
+                // BadMatch while the window is not viewable; the Expose after
+                // the tray maps it brings us back here
+                let panel = match cookie.reply() {
+                    Ok(panel) => panel,
+                    Err(ReplyError::X11Error(_)) => return Ok(()),
+                    Err(e) => return Err(e.into()),
+                };
+                let mut composed = window.format.decode(&panel.data, width, height);
+                for (dst, src) in composed.pixels_mut().zip(label.pixels()) {
+                    let a = src[3] as u16;
+                    for (bg, &fg) in dst.0.iter_mut().zip(&src.0[..3]) {
+                        *bg = ((fg as u16 * a + *bg as u16 * (255 - a) + 127) / 255) as u8;
+                    }
+                }
+                conn.put_image(
+                    xproto::ImageFormat::Z_PIXMAP,
+                    window.id,
+                    self.gc,
+                    width, height,
+                    0, 0,
+                    0,
+                    window.format.depth,
+                    &window.format.encode(&composed),
+                )?;
+            }
+        }
+        conn.flush()?;
+        Ok(())
+    }
+}
+
+fn free_icon(conn: &impl Connection, icon: CachedIcon) -> Result<(), Box<dyn Error>> {
+    if let CachedIcon::Pixmap(pixmap) = icon {
+        conn.free_pixmap(pixmap)?;
+    }
+    Ok(())
+}
+
+pub fn render_text_icon(
+    text: &str,
+    badge: &str,
+    style: GroupStyle,
+    font: &FontArc,
+    config: &Config,
+    icon_size: IconSize,
+) -> RgbaImage {
+    let (width, height) = (icon_size.width as f32, icon_size.height as f32);
+
+    // Colors
+    let (bg_color, fg_color) = match style {
+        GroupStyle::Filled | GroupStyle::Outlined => (config.icon.background.rgba(), config.icon.foreground.rgb()),
+        GroupStyle::Inverted => {
+            let [r, g, b] = config.icon.foreground.rgb();
+            ([r, g, b, 255], config.icon.background.rgb())
+        }
+    };
+
+    let mut image = RgbaImage::from_pixel(
+        icon_size.width as u32,
+        icon_size.height as u32,
+        Rgba(bg_color)
+    );
+
+    // font.size is meant for icon.size; keep the proportion when the tray
+    // hands us a different size
+    let factor = width.min(height) / config.icon.size as f32;
+    let mut font_size = config.font.size * factor;
+
+    // A badge takes the bottom right corner, the label gets the rest of the
+    // width. It stays clear of the Caps Lock underline.
+    let badge_scale = PxScale { x: font_size / 2.0, y: font_size / 2.0 };
+    let badge_width = if badge.is_empty() { 0.0 } else { text_width(font, badge_scale, badge) + factor };
+    let label_width = width - badge_width;
+
+    // Shrink labels that would not fit otherwise: long or multi-line ones
+    let lines: Vec<&str> = text.lines().collect();
+    let line_height = |size: f32| {
+        let scaled_font = font.as_scaled(PxScale { x: size, y: size });
+        scaled_font.ascent() - scaled_font.descent()
+    };
+    let widest = lines
+        .iter()
+        .map(|line| text_width(font, PxScale { x: font_size, y: font_size }, line))
+        .fold(0.0, f32::max);
+    let block_height = line_height(font_size) * lines.len() as f32;
+    let shrink = widest > label_width || block_height > height;
+    if shrink {
+        font_size *= ((label_width - 2.0) / widest).min(height / block_height);
+    }
+    // The usual nudge to the left would cut off a label that fills the width
+    let x_offset = if shrink { 0.0 } else { (2.0 * factor).round() };
+
+    let scale = PxScale { x: font_size, y: font_size };
+    let scaled_font = font.as_scaled(scale);
+    let v_metrics = scaled_font.ascent() - scaled_font.descent();
+    let top = (height - v_metrics * lines.len() as f32) / 2.0;
+
+    for (i, line) in lines.iter().enumerate() {
+        let line_width = text_width(font, scale, line);
+        let start_x = ((label_width - line_width) / 2.0).round() - x_offset;
+        let start_y = (top + v_metrics * i as f32 + scaled_font.ascent()).round() - factor.round();
+        draw_text(&mut image, line, font, scale, start_x, start_y, fg_color);
+    }
+    if !badge.is_empty() {
+        let badge_x = (width - badge_width).round();
+        let badge_y = (height + font.as_scaled(badge_scale).descent() - 4.0 * factor).round();
+        draw_text(&mut image, badge, font, badge_scale, badge_x, badge_y, fg_color);
+    }
+    if style == GroupStyle::Outlined {
+        draw_frame(&mut image, factor.round().max(1.0) as u32, fg_color);
+    }
+    image
+}
+
+fn draw_frame(image: &mut RgbaImage, thickness: u32, color: [u8; 3]) {
+    let (width, height) = image.dimensions();
+    let color = Rgba([color[0], color[1], color[2], 255]);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        if x < thickness || y < thickness || x + thickness >= width || y + thickness >= height {
+            *pixel = color;
+        }
+    }
+}
+
+/// Adds the marks of the active lock keys on top of a rendered icon
+pub fn draw_locks(image: &mut RgbaImage, locks: Locks, config: &Config) {
+    let (width, height) = image.dimensions();
+    let factor = width.min(height) as f32 / config.icon.size as f32;
+    let thickness = (2.0 * factor).round().max(1.0) as u32;
+    let color = Rgba(config.indicators.color.rgba());
+
+    let mut fill = |x0: u32, y0: u32, w: u32, h: u32| {
+        for y in y0..(y0 + h).min(height) {
+            for x in x0..(x0 + w).min(width) {
+                image.put_pixel(x, y, color);
+            }
+        }
+    };
+
+    if locks.caps {
+        // Underline across the middle 3/4, just above the bottom edge
+        let margin = width / 8;
+        fill(margin, height.saturating_sub(2 * thickness), width - 2 * margin, thickness);
+    }
+    let dot = thickness + 1;
+    if locks.num {
+        fill(width.saturating_sub(dot + thickness), thickness, dot, dot);
+    }
+    if locks.scroll {
+        fill(thickness, thickness, dot, dot);
+    }
+}
+
+pub fn text_width(font: &FontArc, scale: PxScale, text: &str) -> f32 {
+    let scaled_font = font.as_scaled(scale);
+    text.chars()
+        .map(|c| scaled_font.h_advance(scaled_font.glyph_id(c)))
+        .sum()
+}
+
+/// Blends `text` onto whatever is already in `image` (which may be
+/// translucent), starting at `x` with the baseline at `baseline`.
+pub fn draw_text(
+    image: &mut RgbaImage,
+    text: &str,
+    font: &FontArc,
+    scale: PxScale,
+    x: f32,
+    baseline: f32,
+    fg_color: [u8; 3],
+) {
+    let scaled_font = font.as_scaled(scale);
+    let (width, height) = image.dimensions();
+    let mut current_x = x;
+
+    for c in text.chars() {
+        let glyph_id = scaled_font.glyph_id(c);
+        let glyph = glyph_id.with_scale_and_position(scale, ab_glyph::point(current_x, baseline));
+
+        if let Some(outlined) = font.outline_glyph(glyph) {
+            let bounds = outlined.px_bounds();
+
+            outlined.draw(|x, y, coverage| {
+                let px = x as i32 + bounds.min.x as i32;
+                let py = y as i32 + bounds.min.y as i32;
+
+                if px >= 0 && py >= 0 && (px as u32) < width && (py as u32) < height {
+                    let pixel = image.get_pixel_mut(px as u32, py as u32);
+
+                    // "over" operator with straight (non-premultiplied) alpha
+                    let [bg_r, bg_g, bg_b, bg_a] = pixel.0;
+                    let bg_a = bg_a as f32 / 255.0;
+                    let out_a = coverage + bg_a * (1.0 - coverage);
+                    if out_a <= 0.0 {
+                        return;
+                    }
+
+                    let blend = |bg: u8, fg: u8| -> u8 {
+                        ((bg as f32 * bg_a * (1.0 - coverage) + fg as f32 * coverage) / out_a) as u8
+                    };
+
+                    let r = blend(bg_r, fg_color[0]);
+                    let g = blend(bg_g, fg_color[1]);
+                    let b = blend(bg_b, fg_color[2]);
+
+                    *pixel = Rgba([r, g, b, (out_a * 255.0).round() as u8]);
+                }
+            });
+        }
+        current_x += scaled_font.h_advance(glyph_id);
+    }
+}
@@ -0,0 +1,209 @@
+use std::error::Error;
+
+use x11rb::connection::Connection;
+use x11rb::properties::WmClass;
+use x11rb::protocol::xproto::{self, AtomEnum, ConnectionExt as _};
+
+use crate::config::Rule;
+use crate::layouts::Layouts;
+
+/// Picks the layout for a window that gains focus for the first time,
+/// based on the `[[rules]]` from the config.
+pub struct RuleEngine {
+    net_wm_name: xproto::Atom,
+    utf8_string: xproto::Atom,
+}
+
+/// What the rules can look at
+struct WindowInfo {
+    instance: String,
+    class: String,
+    title: String,
+}
+
+impl RuleEngine {
+    pub fn new(conn: &impl Connection) -> Result<Self, Box<dyn Error>> {
+        Ok(RuleEngine {
+            net_wm_name: conn.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom,
+            utf8_string: conn.intern_atom(false, b"UTF8_STRING")?.reply()?.atom,
+        })
+    }
+
+    /// Returns the group of the first matching rule. Layouts are referred to
+    /// by name, so rules keep working when the layout order changes.
+    pub fn group_for(
+        &self,
+        conn: &impl Connection,
+        window: xproto::Window,
+        rules: &[Rule],
+        layouts: &Layouts,
+    ) -> Result<Option<u8>, Box<dyn Error>> {
+        if rules.is_empty() || window == x11rb::NONE {
+            return Ok(None);
+        }
+        // The window may already be gone, which is not worth an error
+        let Some(info) = self.window_info(conn, window)? else {
+            return Ok(None);
+        };
+
+        let Some(rule) = rules.iter().find(|rule| matches(rule, &info)) else {
+            return Ok(None);
+        };
+        let group = resolve_layout(&rule.layout, layouts);
+        if group.is_none() {
+            eprintln!(
+                "Rule for '{}' wants layout '{}', which is not one of {:?}",
+                info.class, rule.layout, layouts.names
+            );
+        }
+        Ok(group)
+    }
+
+    fn window_info(&self, conn: &impl Connection, window: xproto::Window) -> Result<Option<WindowInfo>, Box<dyn Error>> {
+        let Ok(wm_class) = WmClass::get(conn, window)?.reply() else {
+            return Ok(None);
+        };
+        let (instance, class) = match wm_class {
+            Some(c) => (
+                String::from_utf8_lossy(c.instance()).into_owned(),
+                String::from_utf8_lossy(c.class()).into_owned(),
+            ),
+            None => (String::new(), String::new()),
+        };
+
+        let mut title = self.get_text(conn, window, self.net_wm_name, self.utf8_string)?;
+        if title.is_empty() {
+            title = self.get_text(conn, window, AtomEnum::WM_NAME.into(), AtomEnum::STRING.into())?;
+        }
+
+        Ok(Some(WindowInfo { instance, class, title }))
+    }
+
+    fn get_text(
+        &self,
+        conn: &impl Connection,
+        window: xproto::Window,
+        property: xproto::Atom,
+        type_: xproto::Atom,
+    ) -> Result<String, Box<dyn Error>> {
+        let Ok(reply) = conn.get_property(false, window, property, type_, 0, 1024)?.reply() else {
+            return Ok(String::new());
+        };
+        Ok(String::from_utf8_lossy(&reply.value).into_owned())
+    }
+}
+
+fn matches(rule: &Rule, info: &WindowInfo) -> bool {
+    let class_ok = rule.class.as_ref().is_none_or(|class| {
+        class.eq_ignore_ascii_case(&info.class) || class.eq_ignore_ascii_case(&info.instance)
+    });
+    let title_ok = rule.title.as_ref().is_none_or(|title| title.0.is_match(&info.title));
+    class_ok && title_ok
+}
+
+/// Finds a group by its XKB name ("Russian"), its XKB layout with an
+/// optional variant ("us", "us(dvorak)") or its label ("RU", also a custom one)
+pub fn resolve_layout(layout: &str, layouts: &Layouts) -> Option<u8> {
+    let by_name = || layouts.names.iter().position(|name| name.eq_ignore_ascii_case(layout));
+    let by_symbol = || {
+        let (symbol, variant) = match layout.split_once('(') {
+            Some((symbol, variant)) => (symbol, Some(variant.trim_end_matches(')'))),
+            None => (layout, None),
+        };
+        layouts.symbols.iter().position(|s| {
+            s.layout.eq_ignore_ascii_case(symbol) && variant.is_none_or(|variant| s.variant.eq_ignore_ascii_case(variant))
+        })
+    };
+    let by_label = || {
+        (layouts.codes.iter().position(|code| code.eq_ignore_ascii_case(layout)))
+            .or_else(|| layouts.labels.iter().position(|label| label.eq_ignore_ascii_case(layout)))
+    };
+
+    by_name().or_else(by_symbol).or_else(by_label).map(|i| i as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use regex_lite::Regex;
+
+    use super::*;
+    use crate::config::{LabelSection, TitlePattern};
+    use crate::layouts::Symbol;
+
+    fn layouts() -> Layouts {
+        let names = ["English (US)", "English (Dvorak)", "Russian"].map(String::from).to_vec();
+        let symbols = [("us", ""), ("us", "dvorak"), ("ru", "")]
+            .map(|(layout, variant)| Symbol { layout: layout.to_string(), variant: variant.to_string() })
+            .to_vec();
+        let mut layouts = Layouts::new(names, Some(symbols));
+        let section = LabelSection {
+            overrides: HashMap::from([("ru".to_string(), "РУ".to_string())]),
+            ..Default::default()
+        };
+        layouts.relabel(&section);
+        layouts
+    }
+
+    fn rule(class: Option<&str>, title: Option<&str>) -> Rule {
+        Rule {
+            class: class.map(String::from),
+            title: title.map(|title| TitlePattern(Regex::new(title).unwrap())),
+            layout: "RU".to_string(),
+        }
+    }
+
+    fn window(instance: &str, class: &str, title: &str) -> WindowInfo {
+        WindowInfo { instance: instance.to_string(), class: class.to_string(), title: title.to_string() }
+    }
+
+    #[test]
+    fn layout_by_name() {
+        let layouts = layouts();
+        assert_eq!(resolve_layout("Russian", &layouts), Some(2));
+        assert_eq!(resolve_layout("english (dvorak)", &layouts), Some(1));
+    }
+
+    #[test]
+    fn layout_by_symbol() {
+        let layouts = layouts();
+        // Without a variant the first group with that layout wins
+        assert_eq!(resolve_layout("us", &layouts), Some(0));
+        assert_eq!(resolve_layout("us(dvorak)", &layouts), Some(1));
+        assert_eq!(resolve_layout("US(Dvorak)", &layouts), Some(1));
+        assert_eq!(resolve_layout("us(colemak)", &layouts), None);
+    }
+
+    #[test]
+    fn layout_by_code_or_label() {
+        let layouts = layouts();
+        assert_eq!(resolve_layout("RU", &layouts), Some(2));
+        assert_eq!(resolve_layout("en", &layouts), Some(0));
+        assert_eq!(resolve_layout("РУ", &layouts), Some(2));
+        assert_eq!(resolve_layout("DE", &layouts), None);
+    }
+
+    #[test]
+    fn class_matches_either_part() {
+        let firefox = window("Navigator", "firefox", "Mozilla Firefox");
+        assert!(matches(&rule(Some("Firefox"), None), &firefox));
+        assert!(matches(&rule(Some("navigator"), None), &firefox));
+        assert!(!matches(&rule(Some("fire"), None), &firefox));
+    }
+
+    #[test]
+    fn title_is_searched() {
+        let terminal = window("xterm", "XTerm", "ssh server.ru");
+        assert!(matches(&rule(None, Some(r"\.ru$")), &terminal));
+        assert!(!matches(&rule(None, Some("^vim")), &terminal));
+    }
+
+    #[test]
+    fn class_and_title_both_have_to_match() {
+        let rule = rule(Some("xterm"), Some("ssh"));
+        assert!(matches(&rule, &window("xterm", "XTerm", "ssh server")));
+        assert!(!matches(&rule, &window("xterm", "XTerm", "bash")));
+        assert!(!matches(&rule, &window("urxvt", "URxvt", "ssh server")));
+    }
+}
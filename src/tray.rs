@@ -0,0 +1,236 @@
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    self, AtomEnum, BackPixmap, ChangeWindowAttributesAux, ClientMessageEvent, ConnectionExt as _,
+    CreateWindowAux, EventMask, WindowClass,
+};
+use x11rb::protocol::Event;
+
+use crate::config::BackgroundMode;
+use crate::visual::PixelFormat;
+
+/// The window we dock, plus the visual details needed to draw into it
+pub struct IconWindow {
+    pub id: xproto::Window,
+    pub format: PixelFormat,
+    /// The mode actually in use, after falling back from `Argb` if needed
+    pub background: BackgroundMode,
+    /// The tray's visual, in `Argb` mode
+    pub argb_visual: Option<xproto::Visualid>,
+    /// Created for the ARGB visual; freed along with the window
+    colormap: Option<xproto::Colormap>,
+}
+
+impl IconWindow {
+    /// Whether the window can be docked into a tray that calls for this
+    /// background and visual, or has to be created anew
+    pub fn suits(&self, background: BackgroundMode, argb_visual: Option<xproto::Visualid>) -> bool {
+        self.background == background && (background != BackgroundMode::Argb || self.argb_visual == argb_visual)
+    }
+
+    pub fn destroy(self, conn: &impl Connection) -> Result<(), Box<dyn Error>> {
+        conn.destroy_window(self.id)?;
+        if let Some(colormap) = self.colormap {
+            conn.free_colormap(colormap)?;
+        }
+        Ok(())
+    }
+}
+
+/// The _NET_SYSTEM_TRAY_Sn selection for our screen
+pub fn selection_atom(conn: &impl Connection, screen_num: usize) -> Result<xproto::Atom, Box<dyn Error>> {
+    let tray_atom_name = format!("_NET_SYSTEM_TRAY_S{}", screen_num);
+    Ok(conn.intern_atom(false, tray_atom_name.as_bytes())?.reply()?.atom)
+}
+
+/// Owner of the _NET_SYSTEM_TRAY_Sn selection, if a tray is running
+pub fn find_manager(conn: &impl Connection, tray_atom: xproto::Atom) -> Result<Option<xproto::Window>, Box<dyn Error>> {
+    // Check if there is an owner of the tray atom
+    let manager_reply = conn.get_selection_owner(tray_atom)?.reply()?;
+    let manager_win = manager_reply.owner;
+
+    if manager_win == x11rb::NONE {
+        return Ok(None);
+    }
+    Ok(Some(manager_win))
+}
+
+/// 32-bit visual the tray asks icons to use (_NET_SYSTEM_TRAY_VISUAL), if any
+pub fn argb_visual(
+    conn: &impl Connection,
+    screen: &xproto::Screen,
+    manager_win: xproto::Window,
+) -> Result<Option<xproto::Visualid>, Box<dyn Error>> {
+    let visual_atom = conn.intern_atom(false, b"_NET_SYSTEM_TRAY_VISUAL")?.reply()?.atom;
+    let reply = conn
+        .get_property(false, manager_win, visual_atom, AtomEnum::VISUALID, 0, 1)?
+        .reply()?;
+    let visual = reply.value32().and_then(|mut v| v.next());
+    Ok(visual.filter(|&v| depth_of_visual(screen, v) == Some(32)))
+}
+
+/// Resolves the configured mode against what the tray supports. Without an
+/// ARGB visual a translucent background falls back to `ParentRelative`.
+pub fn effective_background(
+    mode: BackgroundMode,
+    argb_visual: Option<xproto::Visualid>,
+    translucent: bool,
+) -> BackgroundMode {
+    match mode {
+        BackgroundMode::Argb if argb_visual.is_some() => BackgroundMode::Argb,
+        BackgroundMode::Argb if translucent => BackgroundMode::ParentRelative,
+        BackgroundMode::Argb => BackgroundMode::Solid,
+        other => other,
+    }
+}
+
+/// Returns the tray, waiting for one to announce itself with MANAGER if none
+/// is running yet. Needs StructureNotify selected on the root window.
+/// `None` once `timeout` has passed.
+pub fn wait_for_manager(
+    conn: &impl Connection,
+    screen: &xproto::Screen,
+    tray_atom: xproto::Atom,
+    manager_atom: xproto::Atom,
+    timeout: Option<Duration>,
+) -> Result<Option<xproto::Window>, Box<dyn Error>> {
+    if let Some(manager) = find_manager(conn, tray_atom)? {
+        return Ok(Some(manager));
+    }
+    println!("System Tray not running yet, waiting for it...");
+
+    // The timer wakes us up with a ClientMessage to a throwaway window
+    let timer_win = conn.generate_id()?;
+    conn.create_window(
+        0,
+        timer_win,
+        screen.root,
+        0, 0, 1, 1,
+        0,
+        WindowClass::INPUT_ONLY,
+        x11rb::COPY_FROM_PARENT,
+        &CreateWindowAux::new(),
+    )?;
+    let timeout_atom = conn.intern_atom(false, b"_PSA_KB_SWITCHER_TIMEOUT")?.reply()?.atom;
+    if let Some(timeout) = timeout {
+        let (timer_conn, _) = x11rb::connect(None)?;
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            // Fails harmlessly if the tray showed up and the window is gone
+            let _ = crate::send_client_message(&timer_conn, timer_win, timeout_atom);
+        });
+    }
+    conn.flush()?;
+
+    let manager = loop {
+        match conn.wait_for_event()? {
+            Event::ClientMessage(e) if e.type_ == manager_atom => {
+                if let Some(manager) = new_manager(&e, tray_atom) {
+                    break Some(manager);
+                }
+            }
+            Event::ClientMessage(e) if e.type_ == timeout_atom => break None,
+            _ => {}
+        }
+    };
+    conn.destroy_window(timer_win)?;
+    Ok(manager)
+}
+
+/// Asks for DestroyNotify on the tray, to notice when it goes away
+pub fn watch_manager(conn: &impl Connection, manager_win: xproto::Window) -> Result<(), Box<dyn Error>> {
+    conn.change_window_attributes(manager_win, &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY))?;
+    Ok(())
+}
+
+/// A new tray announces itself with a MANAGER message on the root window
+/// (data: timestamp, selection, owner); returns the new owner if the
+/// message is about our tray selection.
+pub fn new_manager(event: &ClientMessageEvent, tray_atom: xproto::Atom) -> Option<xproto::Window> {
+    let data = event.data.as_data32();
+    (data[1] == tray_atom).then_some(data[2])
+}
+
+pub fn dock(conn: &impl Connection, manager_win: xproto::Window, win_id: xproto::Window) -> Result<(), Box<dyn Error>> {
+    let opcode_atom = conn.intern_atom(false, b"_NET_SYSTEM_TRAY_OPCODE")?.reply()?.atom;
+
+    // SYSTEM_TRAY_REQUEST_DOCK
+    let event = ClientMessageEvent {
+        response_type: xproto::CLIENT_MESSAGE_EVENT,
+        format: 32,
+        window: manager_win,
+        type_: opcode_atom,
+        data: xproto::ClientMessageData::from([0, 0, win_id, 0, 0]),
+        sequence: 0,
+    };
+
+    conn.send_event(false, manager_win, EventMask::NO_EVENT, event)?;
+    Ok(())
+}
+
+/// Creates the icon window. In `Argb` mode the window gets the tray's
+/// 32-bit visual and a matching colormap; otherwise it inherits the root
+/// visual, with a ParentRelative background in `ParentRelative` mode.
+pub fn create_icon_window(
+    conn: &impl Connection,
+    screen: &xproto::Screen,
+    background: BackgroundMode,
+    argb_visual: Option<xproto::Visualid>,
+    size: u16,
+    event_mask: EventMask,
+) -> Result<IconWindow, Box<dyn Error>> {
+    let win_id = conn.generate_id()?;
+
+    let mut colormap = None;
+    let (depth, visual_id, actual_visual, win_aux) = match (background, argb_visual) {
+        (BackgroundMode::Argb, Some(visual)) => {
+            let id = conn.generate_id()?;
+            conn.create_colormap(xproto::ColormapAlloc::NONE, id, screen.root, visual)?;
+            colormap = Some(id);
+            let aux = CreateWindowAux::new()
+                .background_pixel(0)
+                .border_pixel(0)
+                .colormap(id);
+            (32, visual, visual, aux)
+        }
+        (BackgroundMode::ParentRelative, _) => {
+            let aux = CreateWindowAux::new().background_pixmap(BackPixmap::PARENT_RELATIVE);
+            (screen.root_depth, x11rb::COPY_FROM_PARENT, screen.root_visual, aux)
+        }
+        _ => {
+            let aux = CreateWindowAux::new().background_pixel(screen.white_pixel);
+            (screen.root_depth, x11rb::COPY_FROM_PARENT, screen.root_visual, aux)
+        }
+    };
+    let win_aux = win_aux.override_redirect(1).event_mask(event_mask);
+
+    conn.create_window(
+        depth,
+        win_id,
+        screen.root,
+        0, 0, size, size,
+        0,
+        WindowClass::INPUT_OUTPUT,
+        visual_id,
+        &win_aux,
+    )?;
+
+    let background = match (background, argb_visual) {
+        (BackgroundMode::Argb, None) => BackgroundMode::Solid,
+        (mode, _) => mode,
+    };
+    let format = PixelFormat::for_visual(conn.setup(), depth, actual_visual)?;
+    let argb_visual = argb_visual.filter(|_| background == BackgroundMode::Argb);
+    Ok(IconWindow { id: win_id, format, background, argb_visual, colormap })
+}
+
+fn depth_of_visual(screen: &xproto::Screen, visual: xproto::Visualid) -> Option<u8> {
+    screen
+        .allowed_depths
+        .iter()
+        .find(|d| d.visuals.iter().any(|v| v.visual_id == visual))
+        .map(|d| d.depth)
+}
@@ -0,0 +1,278 @@
+use std::error::Error;
+
+use image::{Rgba, RgbaImage};
+use x11rb::protocol::xproto::{self, ImageOrder, VisualClass};
+
+/// How a window's visual lays out pixels in a Z_PIXMAP image: channel masks
+/// from the visual, bits per pixel and scanline padding from the setup's
+/// pixmap formats, and the server's image byte order.
+#[derive(Debug, Clone, Copy)]
+pub struct PixelFormat {
+    pub depth: u8,
+    bits_per_pixel: u8,
+    scanline_pad: u8,
+    msb_first: bool,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+    /// Only 32-bit ARGB visuals have one
+    alpha: Option<Channel>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    shift: u32,
+    max: u32,
+}
+
+impl Channel {
+    fn from_mask(mask: u32) -> Self {
+        if mask == 0 {
+            return Channel { shift: 0, max: 0 };
+        }
+        let shift = mask.trailing_zeros();
+        Channel { shift, max: mask >> shift }
+    }
+
+    fn encode(self, value: u8) -> u32 {
+        let scaled = (value as u64 * self.max as u64 + 127) / 255;
+        (scaled as u32) << self.shift
+    }
+
+    fn decode(self, pixel: u32) -> u8 {
+        if self.max == 0 {
+            return 0;
+        }
+        let value = ((pixel >> self.shift) & self.max) as u64;
+        ((value * 255 + self.max as u64 / 2) / self.max as u64) as u8
+    }
+}
+
+impl PixelFormat {
+    pub fn for_visual(setup: &xproto::Setup, depth: u8, visual_id: xproto::Visualid) -> Result<Self, Box<dyn Error>> {
+        let visual = setup
+            .roots
+            .iter()
+            .flat_map(|screen| &screen.allowed_depths)
+            .filter(|d| d.depth == depth)
+            .flat_map(|d| &d.visuals)
+            .find(|v| v.visual_id == visual_id)
+            .ok_or_else(|| format!("ERROR: Visual 0x{:x} with depth {} not found", visual_id, depth))?;
+        if visual.class != VisualClass::TRUE_COLOR && visual.class != VisualClass::DIRECT_COLOR {
+            return Err(format!("ERROR: Visual class {:?} is not supported, a TrueColor visual is required", visual.class).into());
+        }
+
+        let pixmap_format = setup
+            .pixmap_formats
+            .iter()
+            .find(|f| f.depth == depth)
+            .ok_or_else(|| format!("ERROR: The server has no pixmap format for depth {}", depth))?;
+        let bits_per_pixel = pixmap_format.bits_per_pixel;
+        if ![8, 16, 24, 32].contains(&bits_per_pixel) {
+            return Err(format!("ERROR: {} bits per pixel is not supported", bits_per_pixel).into());
+        }
+
+        // Whatever the depth covers beyond the color channels is alpha
+        let depth_mask = if depth >= 32 { u32::MAX } else { (1u32 << depth) - 1 };
+        let alpha_mask = depth_mask & !(visual.red_mask | visual.green_mask | visual.blue_mask);
+
+        Ok(PixelFormat {
+            depth,
+            bits_per_pixel,
+            scanline_pad: pixmap_format.scanline_pad,
+            msb_first: setup.image_byte_order == ImageOrder::MSB_FIRST,
+            red: Channel::from_mask(visual.red_mask),
+            green: Channel::from_mask(visual.green_mask),
+            blue: Channel::from_mask(visual.blue_mask),
+            alpha: (alpha_mask != 0).then(|| Channel::from_mask(alpha_mask)),
+        })
+    }
+
+    /// Bytes per scanline, including padding
+    fn stride(&self, width: u32) -> usize {
+        let pad = self.scanline_pad.max(8) as u32;
+        let bits = width * self.bits_per_pixel as u32;
+        (bits.div_ceil(pad) * pad / 8) as usize
+    }
+
+    /// Image data for put_image. Visuals with alpha get premultiplied
+    /// colors; elsewhere the alpha channel is dropped.
+    pub fn encode(&self, img: &RgbaImage) -> Vec<u8> {
+        let (width, height) = img.dimensions();
+        let stride = self.stride(width);
+        let bytes = self.bits_per_pixel as usize / 8;
+        let mut data = vec![0; stride * height as usize];
+
+        for (x, y, pixel) in img.enumerate_pixels() {
+            let [r, g, b, a] = pixel.0;
+            let value = match self.alpha {
+                Some(alpha) => {
+                    let premultiply = |c: u8| ((c as u16 * a as u16 + 127) / 255) as u8;
+                    self.red.encode(premultiply(r))
+                        | self.green.encode(premultiply(g))
+                        | self.blue.encode(premultiply(b))
+                        | alpha.encode(a)
+                }
+                None => self.red.encode(r) | self.green.encode(g) | self.blue.encode(b),
+            };
+
+            let offset = y as usize * stride + x as usize * bytes;
+            let out = &mut data[offset..offset + bytes];
+            if self.msb_first {
+                out.copy_from_slice(&value.to_be_bytes()[4 - bytes..]);
+            } else {
+                out.copy_from_slice(&value.to_le_bytes()[..bytes]);
+            }
+        }
+        data
+    }
+
+    /// Reads get_image data back into opaque RGBA
+    pub fn decode(&self, data: &[u8], width: u16, height: u16) -> RgbaImage {
+        let stride = self.stride(width as u32);
+        let bytes = self.bits_per_pixel as usize / 8;
+
+        RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+            let offset = y as usize * stride + x as usize * bytes;
+            let Some(raw) = data.get(offset..offset + bytes) else {
+                return Rgba([0, 0, 0, 255]);
+            };
+            let mut word = [0u8; 4];
+            let value = if self.msb_first {
+                word[4 - bytes..].copy_from_slice(raw);
+                u32::from_be_bytes(word)
+            } else {
+                word[..bytes].copy_from_slice(raw);
+                u32::from_le_bytes(word)
+            };
+            Rgba([self.red.decode(value), self.green.decode(value), self.blue.decode(value), 255])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VISUAL: xproto::Visualid = 0x21;
+
+    fn setup(depth: u8, bits_per_pixel: u8, masks: [u32; 3], order: ImageOrder) -> xproto::Setup {
+        let visual = xproto::Visualtype {
+            visual_id: VISUAL,
+            class: VisualClass::TRUE_COLOR,
+            red_mask: masks[0],
+            green_mask: masks[1],
+            blue_mask: masks[2],
+            ..Default::default()
+        };
+        xproto::Setup {
+            image_byte_order: order,
+            pixmap_formats: vec![xproto::Format { depth, bits_per_pixel, scanline_pad: 32 }],
+            roots: vec![xproto::Screen {
+                allowed_depths: vec![xproto::Depth { depth, visuals: vec![visual] }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn format(depth: u8, bits_per_pixel: u8, masks: [u32; 3], order: ImageOrder) -> PixelFormat {
+        PixelFormat::for_visual(&setup(depth, bits_per_pixel, masks, order), depth, VISUAL).unwrap()
+    }
+
+    const RGB565: [u32; 3] = [0xf800, 0x07e0, 0x001f];
+    const RGB888: [u32; 3] = [0xff0000, 0x00ff00, 0x0000ff];
+
+    fn row(pixels: &[[u8; 4]]) -> RgbaImage {
+        RgbaImage::from_fn(pixels.len() as u32, 1, |x, _| Rgba(pixels[x as usize]))
+    }
+
+    #[test]
+    fn rgb565() {
+        let img = row(&[[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]]);
+
+        let lsb = format(16, 16, RGB565, ImageOrder::LSB_FIRST);
+        let data = lsb.encode(&img);
+        // 48 bits padded to 64
+        assert_eq!(data, [0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00, 0, 0]);
+        assert_eq!(lsb.decode(&data, 3, 1), img);
+
+        let msb = format(16, 16, RGB565, ImageOrder::MSB_FIRST);
+        let data = msb.encode(&img);
+        assert_eq!(data, [0xf8, 0x00, 0x07, 0xe0, 0x00, 0x1f, 0, 0]);
+        assert_eq!(msb.decode(&data, 3, 1), img);
+    }
+
+    #[test]
+    fn rgb565_rounds_to_nearest() {
+        let lsb = format(16, 16, RGB565, ImageOrder::LSB_FIRST);
+        let img = row(&[[0x80, 0x40, 0x20, 255]]);
+        // 0x80 -> 16/31, 0x40 -> 16/63, 0x20 -> 4/31
+        assert_eq!(lsb.encode(&img), [0x04, 0x82, 0, 0]);
+        let back = lsb.decode(&lsb.encode(&img), 1, 1);
+        for (a, b) in back.as_raw().iter().zip(img.as_raw()) {
+            assert!(a.abs_diff(*b) <= 4, "{:?} vs {:?}", back, img);
+        }
+    }
+
+    #[test]
+    fn packed_24_bpp() {
+        let img = row(&[[0x11, 0x22, 0x33, 255], [0x44, 0x55, 0x66, 255]]);
+
+        let lsb = format(24, 24, RGB888, ImageOrder::LSB_FIRST);
+        let data = lsb.encode(&img);
+        assert_eq!(data, [0x33, 0x22, 0x11, 0x66, 0x55, 0x44, 0, 0]);
+        assert_eq!(lsb.decode(&data, 2, 1), img);
+
+        let msb = format(24, 24, RGB888, ImageOrder::MSB_FIRST);
+        let data = msb.encode(&img);
+        assert_eq!(data, [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0, 0]);
+        assert_eq!(msb.decode(&data, 2, 1), img);
+    }
+
+    #[test]
+    fn depth_24_in_32_bpp() {
+        let img = row(&[[0x11, 0x22, 0x33, 255]]);
+
+        let lsb = format(24, 32, RGB888, ImageOrder::LSB_FIRST);
+        assert!(lsb.alpha.is_none());
+        assert_eq!(lsb.encode(&img), [0x33, 0x22, 0x11, 0x00]);
+        assert_eq!(lsb.decode(&lsb.encode(&img), 1, 1), img);
+
+        let msb = format(24, 32, RGB888, ImageOrder::MSB_FIRST);
+        assert_eq!(msb.encode(&img), [0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(msb.decode(&msb.encode(&img), 1, 1), img);
+    }
+
+    #[test]
+    fn argb_premultiplies() {
+        let img = row(&[[255, 0, 0, 128], [0, 0, 0, 0]]);
+
+        let lsb = format(32, 32, RGB888, ImageOrder::LSB_FIRST);
+        assert_eq!(lsb.encode(&img), [0x00, 0x00, 0x80, 0x80, 0, 0, 0, 0]);
+
+        let msb = format(32, 32, RGB888, ImageOrder::MSB_FIRST);
+        assert_eq!(msb.encode(&img), [0x80, 0x80, 0x00, 0x00, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn decode_short_data_is_black() {
+        let lsb = format(24, 32, RGB888, ImageOrder::LSB_FIRST);
+        let img = lsb.decode(&[0x33, 0x22, 0x11, 0x00], 2, 1);
+        assert_eq!(img.get_pixel(0, 0), &Rgba([0x11, 0x22, 0x33, 255]));
+        assert_eq!(img.get_pixel(1, 0), &Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn rejects_unsupported_visuals() {
+        let mut pseudo = setup(8, 8, [0, 0, 0], ImageOrder::LSB_FIRST);
+        pseudo.roots[0].allowed_depths[0].visuals[0].class = VisualClass::PSEUDO_COLOR;
+        assert!(PixelFormat::for_visual(&pseudo, 8, VISUAL).is_err());
+
+        let odd = setup(12, 12, [0xf00, 0x0f0, 0x00f], ImageOrder::LSB_FIRST);
+        assert!(PixelFormat::for_visual(&odd, 12, VISUAL).is_err());
+
+        let setup = setup(24, 32, RGB888, ImageOrder::LSB_FIRST);
+        assert!(PixelFormat::for_visual(&setup, 24, VISUAL + 1).is_err());
+    }
+}
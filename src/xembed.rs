@@ -0,0 +1,100 @@
+use std::error::Error;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{self, AtomEnum, ClientMessageEvent, ConnectionExt as _, EventMask, PropMode};
+use x11rb::wrapper::ConnectionExt as _;
+
+const XEMBED_VERSION: u32 = 0;
+/// _XEMBED_INFO flag: the embedder should map the window
+const XEMBED_MAPPED: u32 = 1 << 0;
+
+// XEMBED message opcodes (data[1] of an _XEMBED ClientMessage)
+const XEMBED_EMBEDDED_NOTIFY: u32 = 0;
+const XEMBED_FOCUS_IN: u32 = 4;
+const XEMBED_FOCUS_NEXT: u32 = 6;
+const XEMBED_FOCUS_PREV: u32 = 7;
+
+// Details of XEMBED_FOCUS_IN
+const XEMBED_FOCUS_FIRST: u32 = 1;
+const XEMBED_FOCUS_LAST: u32 = 2;
+
+const WM_INSTANCE: &str = "psa-kb-switcher";
+const WM_CLASS: &str = "Psa-kb-switcher";
+const WM_TITLE: &str = "Keyboard layout";
+
+/// Client side of the XEMBED protocol, which the tray uses to embed the icon
+pub struct Xembed {
+    pub atom: xproto::Atom,
+    embedder: Option<xproto::Window>,
+}
+
+impl Xembed {
+    pub fn new(conn: &impl Connection) -> Result<Self, Box<dyn Error>> {
+        Ok(Xembed {
+            atom: conn.intern_atom(false, b"_XEMBED")?.reply()?.atom,
+            embedder: None,
+        })
+    }
+
+    /// Sets _XEMBED_INFO (asking the tray to map the window once embedded)
+    /// and the names panels use to order and filter icons. Must happen
+    /// before docking; the window is never mapped by us.
+    pub fn setup_window(&self, conn: &impl Connection, win: xproto::Window) -> Result<(), Box<dyn Error>> {
+        let info_atom = conn.intern_atom(false, b"_XEMBED_INFO")?.reply()?.atom;
+        conn.change_property32(PropMode::REPLACE, win, info_atom, info_atom, &[XEMBED_VERSION, XEMBED_MAPPED])?;
+
+        let wm_class = format!("{}\0{}\0", WM_INSTANCE, WM_CLASS);
+        conn.change_property8(PropMode::REPLACE, win, AtomEnum::WM_CLASS, AtomEnum::STRING, wm_class.as_bytes())?;
+        conn.change_property8(PropMode::REPLACE, win, AtomEnum::WM_NAME, AtomEnum::STRING, WM_TITLE.as_bytes())?;
+
+        let net_wm_name = conn.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom;
+        let utf8_string = conn.intern_atom(false, b"UTF8_STRING")?.reply()?.atom;
+        conn.change_property8(PropMode::REPLACE, win, net_wm_name, utf8_string, WM_TITLE.as_bytes())?;
+        Ok(())
+    }
+
+    /// The tray is gone; the next one will send a new EMBEDDED_NOTIFY
+    pub fn reset(&mut self) {
+        self.embedder = None;
+    }
+
+    /// Handles an _XEMBED message from the tray
+    pub fn handle_message(&mut self, conn: &impl Connection, event: &ClientMessageEvent) -> Result<(), Box<dyn Error>> {
+        let [time, opcode, detail, data1, data2] = event.data.as_data32();
+        match opcode {
+            XEMBED_EMBEDDED_NOTIFY => {
+                println!("Embedded into tray window 0x{:x} (XEMBED version {})", data1, data2);
+                self.embedder = Some(data1);
+            }
+            // The icon never takes keyboard focus: pass it straight on
+            XEMBED_FOCUS_IN => {
+                let next = match detail {
+                    XEMBED_FOCUS_FIRST => XEMBED_FOCUS_NEXT,
+                    XEMBED_FOCUS_LAST => XEMBED_FOCUS_PREV,
+                    _ => return Ok(()),
+                };
+                self.send(conn, time, next)?;
+            }
+            // Activation, focus out and modality do not affect the icon
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn send(&self, conn: &impl Connection, time: u32, opcode: u32) -> Result<(), Box<dyn Error>> {
+        let Some(embedder) = self.embedder else {
+            return Ok(());
+        };
+        let event = ClientMessageEvent {
+            response_type: xproto::CLIENT_MESSAGE_EVENT,
+            format: 32,
+            window: embedder,
+            type_: self.atom,
+            data: xproto::ClientMessageData::from([time, opcode, 0, 0, 0]),
+            sequence: 0,
+        };
+        conn.send_event(false, embedder, EventMask::NO_EVENT, event)?;
+        conn.flush()?;
+        Ok(())
+    }
+}